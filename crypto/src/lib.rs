@@ -7,7 +7,7 @@ use cbc::cipher::{BlockDecryptMut, BlockEncryptMut};
 use hmac::{Hmac, Mac};
 use once_cell::sync::Lazy;
 use rand::{random, Rng};
-use rsa::{BigUint, Oaep, Pkcs1v15Encrypt, Pss, RsaPublicKey};
+use rsa::{BigUint, Oaep, Pkcs1v15Encrypt, Pss, RsaPrivateKey, RsaPublicKey};
 use sha1::Sha1;
 use std::convert::TryInto;
 use thiserror::Error;
@@ -55,6 +55,11 @@ pub struct SessionKeys {
 }
 
 pub fn generate_session_key(nonce: Option<&[u8; 16]>) -> SessionKeys {
+    generate_session_key_with(&SYSTEM_PUBLIC_KEY, nonce)
+}
+
+/// Generate a session key encrypted with a different public key than the steam "system" key
+pub fn generate_session_key_with(key: &RsaPublicKey, nonce: Option<&[u8; 16]>) -> SessionKeys {
     let mut rng = rand::thread_rng();
     let plain: [u8; 32] = rng.gen();
 
@@ -63,9 +68,9 @@ pub fn generate_session_key(nonce: Option<&[u8; 16]>) -> SessionKeys {
             let mut data = [0; 48];
             data[0..32].copy_from_slice(&plain);
             data[32..48].copy_from_slice(nonce);
-            encrypt_with_key(&SYSTEM_PUBLIC_KEY, &data)
+            encrypt_with_key(key, &data)
         }
-        None => encrypt_with_key(&SYSTEM_PUBLIC_KEY, &plain),
+        None => encrypt_with_key(key, &plain),
     }
     .expect("Invalid crypt setup");
 
@@ -79,6 +84,10 @@ pub fn encrypt_with_key(key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
         .map_err(RsaError)?)
 }
 
+pub fn decrypt_with_key(key: &RsaPrivateKey, data: &[u8]) -> Result<Vec<u8>> {
+    Ok(key.decrypt(Oaep::new::<Sha1>(), data).map_err(RsaError)?)
+}
+
 pub fn encrypt_with_key_pkcs1(key: &RsaPublicKey, data: &[u8]) -> Result<Vec<u8>> {
     let mut rng = rand::thread_rng();
     Ok(key
@@ -0,0 +1,195 @@
+use protobuf::{CodedInputStream, Enum};
+use std::collections::HashMap;
+use std::env::args;
+use std::error::Error;
+use steam_vent::capture::{Capture, Direction};
+use steam_vent::proto::enums_clientserver::EMsg;
+use steam_vent::proto::steammessages_auth_steamclient::*;
+use steam_vent::proto::steammessages_clientserver::*;
+use steam_vent::proto::steammessages_clientserver_login::*;
+use steam_vent::proto::steammessages_gameservers_steamclient::*;
+use steam_vent::proto::steammessages_player_steamclient::*;
+use steam_vent::proto::{RpcMessage, RpcMessageWithKind, RpcMethod};
+use steam_vent::RawNetMessage;
+
+// pretty-print a capture created with `steam_vent::capture::Recorder`
+//
+// known message kinds and service methods are decoded into their typed messages,
+// other protobuf bodies are printed as their raw fields
+fn main() -> Result<(), Box<dyn Error>> {
+    let path = args().nth(1).expect("no capture file");
+    let capture = Capture::open(path)?;
+    // service method responses only contain the job id of the request, not the method name
+    let mut methods = HashMap::new();
+
+    for captured in capture.messages() {
+        let message = &captured.message;
+        let direction = match captured.direction {
+            Direction::Incoming => "<-",
+            Direction::Outgoing => "->",
+        };
+        let kind = match EMsg::from_i32(message.kind.value()) {
+            Some(kind) => format!("{kind:?}"),
+            None => format!("{}", message.kind.value()),
+        };
+        let method = match &message.header.target_job_name {
+            Some(name) => {
+                // notifications from the server don't get a response
+                if captured.direction == Direction::Outgoing {
+                    methods.insert(message.header.source_job_id, name.to_string());
+                }
+                Some((name.to_string(), false))
+            }
+            None if message.kind == EMsg::k_EMsgServiceMethodResponse => methods
+                .remove(&message.header.target_job_id)
+                .map(|name| (name, true)),
+            None => None,
+        };
+        println!(
+            "[{:>10.3}s] {direction} {kind}{} ({} bytes)",
+            captured.timestamp.as_secs_f64(),
+            method
+                .as_ref()
+                .map(|(name, _)| format!(" {name}"))
+                .unwrap_or_default(),
+            message.data.len()
+        );
+        println!("  header: {:?}", message.header);
+        let decoded = match &method {
+            Some((name, is_response)) => decode_method(name, *is_response, &message.data),
+            None => decode_kind(message),
+        };
+        match decoded {
+            Some(Ok(decoded)) => println!("  {}", decoded.replace('\n', "\n  ")),
+            Some(Err(e)) => {
+                println!("  <malformed: {e}>");
+                print_fields(&message.data, 1);
+            }
+            None if message.is_protobuf => print_fields(&message.data, 1),
+            None => println!("  body: {}", hex(&message.data)),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse<T: RpcMessage>(mut data: &[u8]) -> protobuf::Result<String> {
+    Ok(format!("{:#?}", T::parse(&mut data)?))
+}
+
+/// Decode the body of known message kinds
+fn decode_kind(message: &RawNetMessage) -> Option<protobuf::Result<String>> {
+    macro_rules! kinds {
+        ($($message:ty),* $(,)?) => {
+            $(
+                if message.is_protobuf && message.kind == <$message as RpcMessageWithKind>::KIND {
+                    return Some(parse::<$message>(&message.data));
+                }
+            )*
+        };
+    }
+    kinds!(
+        CMsgClientHello,
+        CMsgClientLogon,
+        CMsgClientLogonResponse,
+        CMsgClientLogOff,
+        CMsgClientLoggedOff,
+        CMsgClientHeartBeat,
+        CMsgClientCMList,
+        CMsgClientAccountInfo,
+        CMsgClientSessionToken,
+        CMsgClientServersAvailable,
+        CMsgClientLicenseList,
+        CMsgClientGamesPlayed,
+        CMsgClientWalletInfoUpdate,
+    );
+    None
+}
+
+/// Decode the request or response of known service methods
+fn decode_method(name: &str, is_response: bool, data: &[u8]) -> Option<protobuf::Result<String>> {
+    macro_rules! methods {
+        ($($request:ty),* $(,)?) => {
+            $(
+                if name == <$request as RpcMethod>::METHOD_NAME {
+                    return Some(if is_response {
+                        parse::<<$request as RpcMethod>::Response>(data)
+                    } else {
+                        parse::<$request>(data)
+                    });
+                }
+            )*
+        };
+    }
+    methods!(
+        CAuthentication_GetPasswordRSAPublicKey_Request,
+        CAuthentication_BeginAuthSessionViaCredentials_Request,
+        CAuthentication_UpdateAuthSessionWithSteamGuardCode_Request,
+        CAuthentication_PollAuthSessionStatus_Request,
+        CAuthentication_AccessToken_GenerateForApp_Request,
+        CGameServers_GetServerList_Request,
+        CPlayer_GetOwnedGames_Request,
+        CPlayer_GetGameBadgeLevels_Request,
+    );
+    None
+}
+
+fn print_fields(data: &[u8], depth: usize) {
+    if let Err(e) = try_print_fields(data, depth) {
+        println!("{:indent$}<malformed: {e}>", "", indent = depth * 2);
+    }
+}
+
+fn try_print_fields(data: &[u8], depth: usize) -> protobuf::Result<()> {
+    let indent = depth * 2;
+    let mut input = CodedInputStream::from_bytes(data);
+    while let Some(tag) = input.read_raw_tag_or_eof()? {
+        let field = tag >> 3;
+        match tag & 7 {
+            0 => println!("{:indent$}{field}: {}", "", input.read_raw_varint64()?),
+            1 => println!("{:indent$}{field}: {:#x}", "", input.read_fixed64()?),
+            5 => println!("{:indent$}{field}: {:#x}", "", input.read_fixed32()?),
+            2 => {
+                let bytes = input.read_bytes()?;
+                match std::str::from_utf8(&bytes) {
+                    Ok(text) if !text.chars().any(char::is_control) => {
+                        println!("{:indent$}{field}: {text:?}", "")
+                    }
+                    _ if is_message(&bytes) => {
+                        println!("{:indent$}{field}: {{", "");
+                        print_fields(&bytes, depth + 1);
+                        println!("{:indent$}}}", "");
+                    }
+                    _ => println!("{:indent$}{field}: {}", "", hex(&bytes)),
+                }
+            }
+            wire_type => {
+                println!("{:indent$}{field}: <unsupported wire type {wire_type}>", "");
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check if the bytes parse as a protobuf message
+fn is_message(data: &[u8]) -> bool {
+    let mut input = CodedInputStream::from_bytes(data);
+    let mut parse = || -> protobuf::Result<bool> {
+        while let Some(tag) = input.read_raw_tag_or_eof()? {
+            match tag & 7 {
+                0 => drop(input.read_raw_varint64()?),
+                1 => drop(input.read_fixed64()?),
+                5 => drop(input.read_fixed32()?),
+                2 => drop(input.read_bytes()?),
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    };
+    !data.is_empty() && parse().unwrap_or(false)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,45 @@
+use std::error::Error;
+use steam_vent::connection::UnAuthenticatedConnection;
+use steam_vent::proto::steammessages_gameservers_steamclient::{
+    cgame_servers_get_server_list_response, CGameServers_GetServerList_Request,
+    CGameServers_GetServerList_Response,
+};
+use steam_vent::testing::MockCmServer;
+use steam_vent::{ConnectionTrait, Transport};
+
+// run a client against a local mock server, without connecting to steam
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    tracing_subscriber::fmt::init();
+
+    for transport in [Transport::WebSocket, Transport::Tcp] {
+        let server = MockCmServer::start(transport).await?;
+        server.add_handler(|req: CGameServers_GetServerList_Request| async move {
+            Ok(CGameServers_GetServerList_Response {
+                servers: vec![cgame_servers_get_server_list_response::Server {
+                    name: Some(format!("mock server for {}", req.filter()).into_bytes()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        });
+
+        let connection = UnAuthenticatedConnection::connect_with_config(
+            &server.server_list(),
+            &server.transport_config(),
+        )
+        .await?
+        .anonymous()
+        .await?;
+        println!("{transport:?}: logged in as {:?}", connection.steam_id());
+
+        let mut req = CGameServers_GetServerList_Request::new();
+        req.set_filter(r"\appid\440".into());
+        let response = connection.service_method(req).await?;
+        for server in response.servers {
+            println!("{transport:?}: {}", String::from_utf8_lossy(server.name()));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use rand::{thread_rng, Rng};
+use std::time::Duration;
+
+/// Exponential backoff policy for retrying failed operations
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+    jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            factor: 2,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// Set the delay before the first retry
+    pub fn with_initial(self, initial: Duration) -> Self {
+        Backoff { initial, ..self }
+    }
+
+    /// Set the maximum delay between retries
+    pub fn with_max(self, max: Duration) -> Self {
+        Backoff { max, ..self }
+    }
+
+    /// Set the factor by which the delay grows after every attempt
+    pub fn with_factor(self, factor: u32) -> Self {
+        Backoff { factor, ..self }
+    }
+
+    /// Randomly shorten every delay by up to this fraction, to prevent clients from retrying in lockstep
+    ///
+    /// The jitter is clamped between `0.0` (no jitter, the default) and `1.0`.
+    pub fn with_jitter(self, jitter: f64) -> Self {
+        Backoff {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    /// Get the delay before the retry with the given (zero based) attempt number
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let multiplier = self.factor.saturating_pow(attempt);
+        let delay = self.initial.saturating_mul(multiplier).min(self.max);
+        if self.jitter > 0.0 {
+            delay.mul_f64(1.0 - self.jitter * thread_rng().gen::<f64>())
+        } else {
+            delay
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exponential_delay() {
+        let backoff = Backoff::default()
+            .with_initial(Duration::from_millis(100))
+            .with_factor(3);
+        assert_eq!(backoff.delay(0), Duration::from_millis(100));
+        assert_eq!(backoff.delay(1), Duration::from_millis(300));
+        assert_eq!(backoff.delay(2), Duration::from_millis(900));
+    }
+
+    #[test]
+    fn delay_capped_at_max() {
+        let backoff = Backoff::default().with_max(Duration::from_secs(5));
+        assert_eq!(backoff.delay(3), Duration::from_secs(5));
+        // doesn't overflow for large attempt numbers
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jitter_shortens_delay() {
+        let backoff = Backoff::default().with_jitter(0.5);
+        for _ in 0..100 {
+            let delay = backoff.delay(0);
+            assert!(delay <= Duration::from_secs(1));
+            assert!(delay >= Duration::from_millis(500));
+        }
+    }
+
+    #[test]
+    fn jitter_clamped() {
+        let backoff = Backoff::default().with_jitter(2.0);
+        assert_eq!(backoff.jitter, 1.0);
+        let backoff = Backoff::default().with_jitter(-1.0);
+        assert_eq!(backoff.jitter, 0.0);
+    }
+}
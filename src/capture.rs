@@ -0,0 +1,388 @@
+//! Record the messages of a session to a capture file and replay them later
+//!
+//! A [`Recorder`] can be added to a connection with [`ConnectionOptions::with_recorder`](crate::ConnectionOptions::with_recorder),
+//! after which every message sent and received by the connection is written to the capture.
+//!
+//! A capture can be replayed with [`replay`], which creates a transport that can be passed to
+//! [`UnAuthenticatedConnection::from_sender_receiver`](crate::connection::UnAuthenticatedConnection::from_sender_receiver).
+
+use crate::message::{EncodedBody, MultiBodyIter};
+use crate::net::{JobId, NetworkError, ParseLimits, RawNetMessage};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use protobuf::Enum;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::future::ready;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::iter;
+use std::path::Path;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use steam_vent_proto::enums_clientserver::EMsg;
+use steam_vent_proto::steammessages_clientserver_login::CMsgClientLogon;
+use tokio::spawn;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::PollSender;
+use tracing::{debug, warn};
+
+type Result<T, E = NetworkError> = std::result::Result<T, E>;
+
+const MAGIC: [u8; 8] = *b"SVCAP\0\x01\0";
+
+/// Whether a message was sent or received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Received from steam
+    Incoming,
+    /// Sent to steam
+    Outgoing,
+}
+
+/// A single message from a capture
+#[derive(Debug, Clone)]
+pub struct CapturedMessage {
+    pub direction: Direction,
+    /// Time since the start of the capture
+    pub timestamp: Duration,
+    pub message: RawNetMessage,
+}
+
+/// Writes the messages of a connection to a capture
+///
+/// Multi messages are recorded as their individual child messages.
+/// The recorder can be cloned to record multiple connections into the same capture.
+///
+/// Messages are written to the capture by a background thread, so recording never blocks the connection.
+///
+/// # Credentials
+///
+/// The password, access token and other secrets in a `CMsgClientLogon` are removed before the message is recorded.
+/// All other messages are recorded as-is, this includes the messages of the `Authentication` service that are used
+/// during [`login`](crate::connection::UnAuthenticatedConnection::login), which contain the (encrypted) password and
+/// the refresh and access tokens for the account. Captures of authenticated sessions should be treated as secrets.
+#[derive(Clone)]
+pub struct Recorder {
+    records: std_mpsc::Sender<Record>,
+    started: Instant,
+}
+
+/// A message waiting to be written to the capture
+struct Record {
+    direction: Direction,
+    timestamp: Duration,
+    message: RawNetMessage,
+}
+
+impl Debug for Recorder {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder").finish_non_exhaustive()
+    }
+}
+
+impl Recorder {
+    /// Create a capture file at the path, overwriting any existing file
+    pub fn create(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Write a capture to any writer
+    ///
+    /// The writer is moved to a background thread, which stops once all clones of the recorder are dropped.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> std::io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        let (records, rx) = std_mpsc::channel();
+        thread::Builder::new()
+            .name("steam-vent-recorder".into())
+            .spawn(move || write_records(writer, rx))?;
+        Ok(Recorder {
+            records,
+            started: Instant::now(),
+        })
+    }
+
+    pub(crate) fn record(&self, direction: Direction, message: &RawNetMessage) {
+        if message.kind == EMsg::k_EMsgMulti {
+            for child in MultiBodyIter::new(message.data.clone(), ParseLimits::default()) {
+                match child {
+                    Ok(child) => self.record(direction, &child),
+                    Err(e) => warn!(error = ?e, "failed to decode multi message for capture"),
+                }
+            }
+            return;
+        }
+
+        let message = if message.kind == EMsg::k_EMsgClientLogon {
+            match redact_logon(message) {
+                Some(message) => message,
+                None => return,
+            }
+        } else {
+            message.clone()
+        };
+        let record = Record {
+            direction,
+            timestamp: self.started.elapsed(),
+            message,
+        };
+        if self.records.send(record).is_err() {
+            debug!("capture writer stopped");
+        }
+    }
+}
+
+/// Remove the credentials from a logon message, the message isn't recorded at all if that fails
+fn redact_logon(message: &RawNetMessage) -> Option<RawNetMessage> {
+    let mut logon = match message.clone().into_message::<CMsgClientLogon>() {
+        Ok(logon) => logon,
+        Err(e) => {
+            warn!(error = ?e, "failed to decode logon message for capture");
+            return None;
+        }
+    };
+    logon.clear_password();
+    logon.clear_access_token();
+    logon.clear_login_key();
+    logon.clear_auth_code();
+    logon.clear_two_factor_code();
+    logon.clear_game_server_token();
+    logon.clear_sha_sentryfile();
+    match RawNetMessage::from_message(message.header.clone(), logon) {
+        Ok(message) => Some(message),
+        Err(e) => {
+            warn!(error = ?e, "failed to encode redacted logon message for capture");
+            None
+        }
+    }
+}
+
+/// Write records to the capture until all recorders are dropped
+fn write_records<W: Write>(mut writer: W, records: std_mpsc::Receiver<Record>) {
+    while let Ok(record) = records.recv() {
+        // write everything that is queued before flushing
+        let result = iter::once(record)
+            .chain(records.try_iter())
+            .try_for_each(|record| record.write(&mut writer))
+            // keep the capture usable when the application doesn't exit cleanly
+            .and_then(|_| writer.flush());
+        if let Err(e) = result {
+            warn!(error = ?e, "failed to write message to capture");
+        }
+    }
+}
+
+impl Record {
+    fn write<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let message = &self.message;
+        let len = message.header_buffer.len() + message.data.len();
+        writer.write_u8(match self.direction {
+            Direction::Incoming => 0,
+            Direction::Outgoing => 1,
+        })?;
+        writer.write_u64::<LittleEndian>(self.timestamp.as_micros() as u64)?;
+        writer.write_u32::<LittleEndian>(len as u32)?;
+        writer.write_all(&message.header_buffer)?;
+        writer.write_all(&message.data)
+    }
+}
+
+/// Record the messages passing through a transport
+pub(crate) fn record_transport<
+    Sender: Sink<RawNetMessage, Error = NetworkError>,
+    Receiver: Stream<Item = Result<RawNetMessage>>,
+>(
+    sender: Sender,
+    receiver: Receiver,
+    recorder: Option<Recorder>,
+) -> (
+    impl Sink<RawNetMessage, Error = NetworkError>,
+    impl Stream<Item = Result<RawNetMessage>>,
+) {
+    let outgoing = recorder.clone();
+    let sender = sender.with(move |message: RawNetMessage| {
+        if let Some(recorder) = &outgoing {
+            recorder.record(Direction::Outgoing, &message);
+        }
+        ready(Ok(message))
+    });
+    let receiver = receiver.inspect(move |message| {
+        if let (Some(recorder), Ok(message)) = (&recorder, message) {
+            recorder.record(Direction::Incoming, message);
+        }
+    });
+    (sender, receiver)
+}
+
+/// The messages of a recorded session
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    messages: Vec<CapturedMessage>,
+}
+
+impl Capture {
+    /// Read a capture file
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a capture from any reader
+    pub fn read<R: Read>(mut reader: R) -> std::io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "not a steam-vent capture",
+            ));
+        }
+
+        let mut messages = Vec::new();
+        loop {
+            let direction = match reader.read_u8() {
+                Ok(0) => Direction::Incoming,
+                Ok(1) => Direction::Outgoing,
+                Ok(direction) => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid message direction {direction}"),
+                    ))
+                }
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let timestamp = Duration::from_micros(reader.read_u64::<LittleEndian>()?);
+            let len = reader.read_u32::<LittleEndian>()?;
+            let mut data = Vec::new();
+            reader.by_ref().take(len as u64).read_to_end(&mut data)?;
+            if data.len() != len as usize {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let message = RawNetMessage::read(data)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            messages.push(CapturedMessage {
+                direction,
+                timestamp,
+                message,
+            });
+        }
+        Ok(Capture { messages })
+    }
+
+    pub fn messages(&self) -> &[CapturedMessage] {
+        &self.messages
+    }
+
+    pub fn into_messages(self) -> Vec<CapturedMessage> {
+        self.messages
+    }
+}
+
+/// Create a transport that plays back a capture
+///
+/// The recorded incoming messages are sent in order, whenever the next recorded message is an outgoing message
+/// the playback waits until the connection sends a message of the same kind. Other messages sent by the connection,
+/// like heartbeats, are ignored. Job ids from the capture are replaced by the job ids used by the connection, so
+/// responses reach the jobs waiting for them.
+///
+/// Messages are played back without the recorded delays, the transport is closed once the capture is exhausted.
+pub fn replay(
+    capture: Capture,
+) -> (
+    impl Sink<BytesMut, Error = NetworkError>,
+    impl Stream<Item = Result<BytesMut>>,
+) {
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(16);
+    let (incoming_tx, incoming_rx) = mpsc::channel(16);
+    spawn(play(capture, outgoing_rx, incoming_tx));
+    (
+        PollSender::new(outgoing_tx).sink_map_err(|_| NetworkError::ConnectionClosed),
+        ReceiverStream::new(incoming_rx),
+    )
+}
+
+async fn play(
+    capture: Capture,
+    mut outgoing: mpsc::Receiver<BytesMut>,
+    incoming: mpsc::Sender<Result<BytesMut>>,
+) {
+    // messages sent by the connection that haven't been matched to the capture yet
+    let mut sent = VecDeque::new();
+    let mut job_ids = HashMap::new();
+
+    for captured in capture.into_messages() {
+        let recorded = captured.message;
+        match captured.direction {
+            Direction::Incoming => {
+                let message = match job_ids.get(&recorded.header.target_job_id) {
+                    Some(job_id) => with_target_job(recorded, *job_id),
+                    None => recorded,
+                };
+                debug!(kind = ?message.kind, "replaying message");
+                if incoming.send(Ok(message.into_bytes())).await.is_err() {
+                    return;
+                }
+            }
+            Direction::Outgoing => loop {
+                let Some(message) = next_sent(&mut sent, &mut outgoing).await else {
+                    debug!("connection closed during replay");
+                    return;
+                };
+                if message.kind != recorded.kind {
+                    debug!(kind = ?message.kind, expected = ?recorded.kind, "ignoring unrecorded message");
+                    continue;
+                }
+                if recorded.header.source_job_id != JobId::NONE {
+                    job_ids.insert(recorded.header.source_job_id, message.header.source_job_id);
+                }
+                break;
+            },
+        }
+    }
+    debug!("end of capture reached");
+}
+
+/// Get the next message sent by the connection, splitting up multi messages
+async fn next_sent(
+    sent: &mut VecDeque<RawNetMessage>,
+    outgoing: &mut mpsc::Receiver<BytesMut>,
+) -> Option<RawNetMessage> {
+    while sent.is_empty() {
+        let message = match RawNetMessage::read(outgoing.recv().await?) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!(error = ?e, "invalid message sent during replay");
+                continue;
+            }
+        };
+        if message.kind == EMsg::k_EMsgMulti {
+            sent.extend(
+                MultiBodyIter::new(message.data, ParseLimits::default()).filter_map(Result::ok),
+            );
+        } else {
+            sent.push_back(message);
+        }
+    }
+    sent.pop_front()
+}
+
+/// Re-encode a recorded message for a different job
+fn with_target_job(message: RawNetMessage, job_id: JobId) -> RawNetMessage {
+    let Some(kind) = EMsg::from_i32(message.kind.value()) else {
+        warn!(kind = ?message.kind, "can't re-encode message of unknown kind for replay");
+        return message;
+    };
+    let mut header = message.header.clone();
+    header.target_job_id = job_id;
+    let body = EncodedBody::from(message.data.clone());
+    match RawNetMessage::from_message_with_kind(header, body, kind, message.is_protobuf) {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            warn!(error = ?e, "failed to re-encode message for replay");
+            message
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use super::{ConnectionTrait, Result};
+use crate::retry::RetryPolicy;
+use crate::service_method::ServiceMethodRequest;
+use std::time::Duration;
+
+/// Options for a single rpc-request or job, overriding the options of the connection
+#[derive(Debug, Clone, Default)]
+pub struct CallOptions {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) retry: Option<RetryPolicy>,
+}
+
+impl CallOptions {
+    /// Set how long to wait for the response
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        CallOptions {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set the retry policy for the request
+    pub fn with_retry(self, retry: RetryPolicy) -> Self {
+        CallOptions {
+            retry: Some(retry),
+            ..self
+        }
+    }
+}
+
+/// A rpc-request with per-call options, created by [`ConnectionTrait::call`]
+#[must_use = "the request is only sent once `send` is called"]
+pub struct ServiceMethodCall<'a, C, Msg> {
+    connection: &'a C,
+    msg: Msg,
+    options: CallOptions,
+}
+
+impl<'a, C: ConnectionTrait, Msg: ServiceMethodRequest> ServiceMethodCall<'a, C, Msg> {
+    pub(crate) fn new(connection: &'a C, msg: Msg) -> Self {
+        ServiceMethodCall {
+            connection,
+            msg,
+            options: CallOptions::default(),
+        }
+    }
+
+    /// Set how long to wait for the response, overriding the timeout of the connection
+    pub fn timeout(self, timeout: Duration) -> Self {
+        ServiceMethodCall {
+            options: self.options.with_timeout(timeout),
+            ..self
+        }
+    }
+
+    /// Set the retry policy for the request, overriding the retry policy of the connection
+    pub fn retry(self, retry: RetryPolicy) -> Self {
+        ServiceMethodCall {
+            options: self.options.with_retry(retry),
+            ..self
+        }
+    }
+
+    /// Send the request and wait for the response
+    pub async fn send(self) -> Result<Msg::Response> {
+        self.connection
+            .service_method_with_options(self.msg, self.options)
+            .await
+    }
+}
@@ -0,0 +1,26 @@
+use super::ReconnectEvent;
+use crate::eresult::EResult;
+use crate::net::NetworkError;
+use std::sync::Arc;
+
+/// Changes in the state of a connection
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ConnectionEvent {
+    /// Steam ended the session, with the reason for it
+    LoggedOff(EResult),
+    /// Steam asked us to connect to a different server
+    ServerRedirect,
+    /// The server closed the websocket, with the close code and reason it gave
+    ///
+    /// This is a normal end of the connection and is followed by [`TransportClosed(None)`](Self::TransportClosed).
+    ServerClosed { code: u16, reason: String },
+    /// The connection to the server was closed, with the error that caused it, if any
+    TransportClosed(Option<Arc<NetworkError>>),
+    /// A heartbeat could not be delivered to the server
+    HeartbeatMissed,
+    /// No messages were received from the server for too long, the connection is considered dead and will be closed
+    HeartbeatTimeout,
+    /// An event from a [`ReconnectingConnection`](super::ReconnectingConnection)
+    Reconnect(ReconnectEvent),
+}
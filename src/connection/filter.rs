@@ -1,16 +1,29 @@
-use crate::message::ServiceMethodNotification;
-use crate::net::{JobId, RawNetMessage};
+use crate::connection::interceptor::{Interceptor, Interceptors, OutgoingAction};
+use crate::connection::ConnectionEvent;
+use crate::connection::Result;
+use crate::eresult::EResult;
+use crate::message::{NetMessage, ServiceMethodNotification};
+use crate::net::{JobId, NetMessageHeader, NetworkError, RawNetMessage};
 use dashmap::DashMap;
 use futures_util::Stream;
-use std::collections::VecDeque;
-use std::pin::pin;
+use protobuf::Message;
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
 use steam_vent_proto::enums_clientserver::EMsg;
+use steam_vent_proto::steammessages_clientserver_login::CMsgClientLoggedOff;
 use steam_vent_proto::MsgKind;
-use tokio::spawn;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::{select, spawn};
 use tokio_stream::StreamExt;
-use tracing::{debug, error};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
 
 #[derive(Clone)]
 pub struct RingBuffer<T>(Arc<Mutex<VecDeque<T>>>);
@@ -48,92 +61,412 @@ impl<T: Clone> RingBuffer<T> {
     }
 }
 
+/// Buffer sizes of the channels used to deliver incoming messages to listeners
+///
+/// A listener that falls further behind than its buffer receives a [`NetworkError::Lagged`] error
+/// instead of blocking the delivery of other messages. Every buffer holds at least one message.
+#[derive(Debug, Clone, Copy)]
+pub struct ListenerCapacities {
+    kind: usize,
+    notification: usize,
+    job_multi: usize,
+    events: usize,
+}
+
+impl Default for ListenerCapacities {
+    fn default() -> Self {
+        ListenerCapacities {
+            kind: 16,
+            notification: 16,
+            job_multi: 16,
+            events: 16,
+        }
+    }
+}
+
+impl ListenerCapacities {
+    /// Set the buffer size for listeners of a message kind
+    pub fn with_kind(self, kind: usize) -> Self {
+        ListenerCapacities {
+            kind: kind.max(1),
+            ..self
+        }
+    }
+
+    /// Set the buffer size for notification listeners
+    pub fn with_notification(self, notification: usize) -> Self {
+        ListenerCapacities {
+            notification: notification.max(1),
+            ..self
+        }
+    }
+
+    /// Set the buffer size for the responses of a multi-response job
+    pub fn with_job_multi(self, job_multi: usize) -> Self {
+        ListenerCapacities {
+            job_multi: job_multi.max(1),
+            ..self
+        }
+    }
+
+    /// Set the buffer size for connection event listeners
+    pub fn with_events(self, events: usize) -> Self {
+        ListenerCapacities {
+            events: events.max(1),
+            ..self
+        }
+    }
+}
+
+pub type MessagePredicate = Box<dyn Fn(&mut PendingMessage) -> bool + Send + Sync>;
+
+/// An incoming message that is checked against the waiters for its kind
+///
+/// The message is decoded at most once per message type, no matter how many waiters look at it.
+pub struct PendingMessage<'a> {
+    raw: &'a RawNetMessage,
+    decoded: HashMap<TypeId, Option<Box<dyn Any>>>,
+}
+
+impl<'a> PendingMessage<'a> {
+    fn new(raw: &'a RawNetMessage) -> Self {
+        PendingMessage {
+            raw,
+            decoded: HashMap::new(),
+        }
+    }
+
+    /// Decode the message, returns `None` if the message can't be decoded as `T`
+    pub fn decode<T: NetMessage + 'static>(&mut self) -> Option<&(NetMessageHeader, T)> {
+        let raw = self.raw;
+        self.decoded
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| {
+                let decoded = raw.clone().into_header_and_message::<T>().ok()?;
+                Some(Box::new(decoded))
+            })
+            .as_ref()?
+            .downcast_ref()
+    }
+}
+
+struct OneKindFilter {
+    id: u64,
+    tx: oneshot::Sender<RawNetMessage>,
+    predicate: Option<MessagePredicate>,
+}
+
+impl OneKindFilter {
+    fn matches(&self, message: &mut PendingMessage) -> bool {
+        self.predicate
+            .as_ref()
+            .map_or(true, |predicate| predicate(message))
+    }
+}
+
+/// Future resolving to the next message of a kind, see [`MessageFilter::one_kind`]
+pub struct KindWaiter {
+    rx: oneshot::Receiver<RawNetMessage>,
+    kind: MsgKind,
+    id: u64,
+    filters: Arc<DashMap<MsgKind, Vec<OneKindFilter>>>,
+}
+
+impl Future for KindWaiter {
+    type Output = std::result::Result<RawNetMessage, oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx)
+    }
+}
+
+impl Drop for KindWaiter {
+    fn drop(&mut self) {
+        if let Some(mut waiters) = self.filters.get_mut(&self.kind) {
+            waiters.retain(|waiter| waiter.id != self.id);
+        }
+        self.filters
+            .remove_if(&self.kind, |_, waiters| waiters.is_empty());
+    }
+}
+
+/// Future resolving to the response for a job, see [`MessageFilter::on_job_id`]
+///
+/// The job is unregistered once the future is dropped.
+pub struct JobWaiter {
+    rx: oneshot::Receiver<RawNetMessage>,
+    id: JobId,
+    filters: Arc<DashMap<JobId, oneshot::Sender<RawNetMessage>>>,
+}
+
+impl Future for JobWaiter {
+    type Output = std::result::Result<RawNetMessage, oneshot::error::RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx)
+    }
+}
+
+impl Drop for JobWaiter {
+    fn drop(&mut self) {
+        // only unregister our own sender, the job id might have been reused by a newer job after a reconnect
+        self.rx.close();
+        self.filters.remove_if(&self.id, |_, tx| tx.is_closed());
+    }
+}
+
+/// Receiver for the responses of a job with multiple responses, see [`MessageFilter::on_job_id_multi`]
+///
+/// The job is unregistered once the receiver is dropped.
+pub struct JobMultiReceiver {
+    rx: mpsc::Receiver<Result<RawNetMessage>>,
+    id: JobId,
+    filters: Arc<DashMap<JobId, mpsc::Sender<Result<RawNetMessage>>>>,
+}
+
+impl JobMultiReceiver {
+    pub async fn recv(&mut self) -> Option<Result<RawNetMessage>> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for JobMultiReceiver {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.filters.remove_if(&self.id, |_, tx| tx.is_closed());
+    }
+}
+
 /// A filter for incoming messages, allowing listing by message type, job id and notifications
 #[derive(Clone)]
 pub struct MessageFilter {
     job_id_filters: Arc<DashMap<JobId, oneshot::Sender<RawNetMessage>>>,
-    job_id_multi_filters: Arc<DashMap<JobId, mpsc::Sender<RawNetMessage>>>,
+    job_id_multi_filters: Arc<DashMap<JobId, mpsc::Sender<Result<RawNetMessage>>>>,
     notification_filters: Arc<DashMap<&'static str, broadcast::Sender<ServiceMethodNotification>>>,
     kind_filters: Arc<DashMap<MsgKind, broadcast::Sender<RawNetMessage>>>,
-    oneshot_kind_filters: Arc<DashMap<MsgKind, oneshot::Sender<RawNetMessage>>>,
+    oneshot_kind_filters: Arc<DashMap<MsgKind, Vec<OneKindFilter>>>,
+    next_waiter_id: Arc<AtomicU64>,
     rest: RingBuffer<RawNetMessage>,
+    events: broadcast::Sender<ConnectionEvent>,
+    capacities: ListenerCapacities,
+    interceptors: Interceptors,
+    last_received: Arc<Mutex<Instant>>,
+}
+
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter::with_capacities(ListenerCapacities::default())
+    }
 }
 
 impl MessageFilter {
-    pub fn new<Input: Stream<Item = crate::connection::Result<RawNetMessage>> + Send + 'static>(
-        source: Input,
-    ) -> Self {
-        let filter = MessageFilter {
+    pub fn with_capacities(capacities: ListenerCapacities) -> Self {
+        MessageFilter {
             job_id_filters: Default::default(),
             job_id_multi_filters: Default::default(),
             kind_filters: Default::default(),
             notification_filters: Default::default(),
             oneshot_kind_filters: Default::default(),
+            next_waiter_id: Default::default(),
             rest: RingBuffer::new(32),
-        };
+            events: broadcast::channel(capacities.events).0,
+            capacities,
+            interceptors: Interceptors::default(),
+            last_received: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn new<Input: Stream<Item = Result<RawNetMessage>> + Send + 'static>(
+        source: Input,
+    ) -> Self {
+        let filter = MessageFilter::default();
+        filter.attach(source, CancellationToken::new());
+        filter
+    }
 
-        let filter_send = filter.clone();
+    /// Start dispatching the messages from `source` to the listeners of this filter.
+    ///
+    /// Existing listeners are kept, which allows moving the listeners over to a new connection.
+    /// The returned token is cancelled once the source stream ends or `detach` is cancelled,
+    /// after `detach` is cancelled no more messages or events from the source are dispatched.
+    pub fn attach<Input: Stream<Item = Result<RawNetMessage>> + Send + 'static>(
+        &self,
+        source: Input,
+        detach: CancellationToken,
+    ) -> CancellationToken {
+        let closed = CancellationToken::new();
+        let closed_send = closed.clone();
+        let filter_send = self.clone();
         spawn(async move {
             let mut source = pin!(source);
-            while let Some(res) = source.next().await {
+            let mut last_error = None;
+            loop {
+                let res = select! {
+                    biased;
+                    _ = detach.cancelled() => {
+                        debug!("message source detached");
+                        closed_send.cancel();
+                        return;
+                    }
+                    res = source.next() => match res {
+                        Some(res) => res,
+                        None => break,
+                    },
+                };
                 match res {
                     Ok(message) => {
+                        last_error = None;
+                        *filter_send.last_received.lock().unwrap() = Instant::now();
+                        let Some(message) = filter_send.interceptors.incoming(message) else {
+                            continue;
+                        };
                         debug!(job_id = message.header.target_job_id.0, kind = ?message.kind, "processing message");
-                        if let Some((_, tx)) = filter_send
-                            .job_id_filters
-                            .remove(&message.header.target_job_id)
-                        {
-                            tx.send(message).ok();
-                        } else if let Some(map_ref) = filter_send
-                            .job_id_multi_filters
-                            .get(&message.header.target_job_id)
-                        {
-                            let tx = map_ref.value();
-                            tx.send(message).await.ok();
-                        } else if let Some((_, tx)) =
-                            filter_send.oneshot_kind_filters.remove(&message.kind)
-                        {
-                            tx.send(message).ok();
-                        } else if message.kind == EMsg::k_EMsgServiceMethod {
-                            if let Ok(notification) =
-                                message.into_message::<ServiceMethodNotification>()
-                            {
-                                debug!(
-                                    job_name = notification.job_name.as_str(),
-                                    "processing notification"
-                                );
-                                if let Some(tx) = filter_send
-                                    .notification_filters
-                                    .get(notification.job_name.as_str())
-                                {
-                                    tx.send(notification).ok();
-                                }
-                            }
-                        } else if let Some(tx) = filter_send.kind_filters.get(&message.kind) {
-                            tx.send(message).ok();
-                        } else if let Some(popped) = filter_send.rest.push(message) {
-                            debug!(kind = ?popped.kind, "Unhandled message");
+                        if message.kind == EMsg::k_EMsgClientLoggedOff {
+                            filter_send.emit_logged_off(&message);
                         }
+                        filter_send.dispatch(message);
+                    }
+                    Err(NetworkError::WebSocketClosed { code, reason }) => {
+                        debug!(code, reason, "connection closed by the server");
+                        last_error = None;
+                        filter_send.emit(ConnectionEvent::ServerClosed { code, reason });
+                        break;
                     }
                     Err(err) => {
                         error!(error = ?err, "Error while reading message");
+                        last_error = Some(Arc::new(err));
                     }
                 }
             }
+            debug!("message source ended");
+            // no responses will arrive for jobs sent over this source anymore
+            filter_send.fail_jobs();
+            filter_send.emit(ConnectionEvent::TransportClosed(last_error));
+            closed_send.cancel();
         });
-        filter
+        closed
+    }
+
+    pub fn add_interceptor(&self, interceptor: Arc<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Pass an outgoing message through the interceptors, returning the message to send, if any
+    ///
+    /// Responses from interceptors are dispatched to the listeners.
+    pub fn intercept_outgoing(&self, message: RawNetMessage) -> Option<RawNetMessage> {
+        match self.interceptors.outgoing(message) {
+            OutgoingAction::Send(message) => Some(message),
+            OutgoingAction::Drop => None,
+            OutgoingAction::Respond(response) => {
+                self.dispatch(response);
+                None
+            }
+        }
+    }
+
+    fn dispatch(&self, message: RawNetMessage) {
+        if let Some((_, tx)) = self.job_id_filters.remove(&message.header.target_job_id) {
+            tx.send(message).ok();
+            return;
+        }
+        if let Some(map_ref) = self.job_id_multi_filters.get(&message.header.target_job_id) {
+            let job_id = *map_ref.key();
+            // don't wait for a slow listener, as that would block all other messages
+            if let Err(TrySendError::Full(_)) = map_ref.value().try_send(Ok(message)) {
+                let tx = map_ref.value().clone();
+                drop(map_ref);
+                warn!(
+                    job_id = job_id.0,
+                    "job listener lagged behind, dropping job"
+                );
+                self.job_id_multi_filters.remove(&job_id);
+                spawn(async move { tx.send(Err(NetworkError::Lagged(1))).await });
+            }
+            return;
+        }
+
+        let waiters = self.take_kind_waiters(&message);
+        if !waiters.is_empty() {
+            for waiter in waiters {
+                waiter.send(message.clone()).ok();
+            }
+        } else if message.kind == EMsg::k_EMsgServiceMethod {
+            if let Ok(notification) = message.into_message::<ServiceMethodNotification>() {
+                debug!(
+                    job_name = notification.job_name.as_str(),
+                    "processing notification"
+                );
+                if let Some(tx) = self
+                    .notification_filters
+                    .get(notification.job_name.as_str())
+                {
+                    tx.send(notification).ok();
+                }
+            }
+        } else if let Some(tx) = self.kind_filters.get(&message.kind) {
+            tx.send(message).ok();
+        } else if let Some(popped) = self.rest.push(message) {
+            debug!(kind = ?popped.kind, "Unhandled message");
+        }
     }
 
-    pub fn on_job_id(&self, id: JobId) -> oneshot::Receiver<RawNetMessage> {
+    /// Remove the waiters for the kind of the message that match the message
+    fn take_kind_waiters(&self, message: &RawNetMessage) -> Vec<oneshot::Sender<RawNetMessage>> {
+        // take the waiters out of the map, so the predicates don't run while the map is locked
+        let Some((_, waiters)) = self.oneshot_kind_filters.remove(&message.kind) else {
+            return Vec::new();
+        };
+        let mut pending = PendingMessage::new(message);
+        let (matched, mut rest): (Vec<_>, Vec<_>) = waiters
+            .into_iter()
+            .partition(|waiter| waiter.matches(&mut pending));
+        // waiters that were dropped in the meantime couldn't remove themselves
+        rest.retain(|waiter| !waiter.tx.is_closed());
+        if !rest.is_empty() {
+            // any waiters added in the meantime were added after the remaining ones
+            self.oneshot_kind_filters
+                .entry(message.kind)
+                .or_default()
+                .splice(0..0, rest);
+        }
+        matched.into_iter().map(|waiter| waiter.tx).collect()
+    }
+
+    pub fn on_job_id(&self, id: JobId) -> JobWaiter {
         let (tx, rx) = oneshot::channel();
         self.job_id_filters.insert(id, tx);
-        rx
+        JobWaiter {
+            rx,
+            id,
+            filters: self.job_id_filters.clone(),
+        }
     }
 
-    pub fn on_job_id_multi(&self, id: JobId) -> mpsc::Receiver<RawNetMessage> {
-        let (tx, rx) = mpsc::channel(16);
+    pub fn on_job_id_multi(&self, id: JobId) -> JobMultiReceiver {
+        let (tx, rx) = mpsc::channel(self.capacities.job_multi);
         self.job_id_multi_filters.insert(id, tx);
-        rx
+        JobMultiReceiver {
+            rx,
+            id,
+            filters: self.job_id_multi_filters.clone(),
+        }
+    }
+
+    /// When the last message was received from any of the attached sources
+    pub fn last_received(&self) -> Instant {
+        *self.last_received.lock().unwrap()
+    }
+
+    /// Fail all jobs that are still waiting for a response, keeping the other listeners
+    pub fn fail_jobs(&self) {
+        self.job_id_filters.clear();
+        self.job_id_multi_filters.clear();
+    }
+
+    /// The number of jobs that are still waiting for a response
+    pub fn outstanding_jobs(&self) -> usize {
+        self.job_id_filters.len() + self.job_id_multi_filters.len()
     }
 
     pub fn complete_job_id_multi(&self, id: JobId) {
@@ -147,7 +480,7 @@ impl MessageFilter {
         let tx = self
             .notification_filters
             .entry(job_name)
-            .or_insert_with(|| broadcast::channel(16).0);
+            .or_insert_with(|| broadcast::channel(self.capacities.notification).0);
         tx.subscribe()
     }
 
@@ -155,17 +488,98 @@ impl MessageFilter {
         let tx = self
             .kind_filters
             .entry(kind.into())
-            .or_insert_with(|| broadcast::channel(16).0);
+            .or_insert_with(|| broadcast::channel(self.capacities.kind).0);
         tx.subscribe()
     }
 
-    pub fn one_kind<K: Into<MsgKind>>(&self, kind: K) -> oneshot::Receiver<RawNetMessage> {
+    /// Wait for the next message of a kind
+    pub fn one_kind<K: Into<MsgKind>>(&self, kind: K) -> KindWaiter {
+        self.one_kind_matching(kind, None)
+    }
+
+    /// Wait for the next message of a kind that matches the predicate
+    ///
+    /// The waiter is removed once the returned future is dropped.
+    pub fn one_kind_matching<K: Into<MsgKind>>(
+        &self,
+        kind: K,
+        predicate: Option<MessagePredicate>,
+    ) -> KindWaiter {
+        let kind = kind.into();
+        let id = self.next_waiter_id.fetch_add(1, Ordering::Relaxed);
         let (tx, rx) = oneshot::channel();
-        self.oneshot_kind_filters.insert(kind.into(), tx);
-        rx
+        self.oneshot_kind_filters
+            .entry(kind)
+            .or_default()
+            .push(OneKindFilter { id, tx, predicate });
+        KindWaiter {
+            rx,
+            kind,
+            id,
+            filters: self.oneshot_kind_filters.clone(),
+        }
     }
 
     pub fn unprocessed(&self) -> Vec<RawNetMessage> {
         self.rest.take()
     }
+
+    /// Remove all listeners, any pending requests or streams will end
+    pub fn close(&self) {
+        self.job_id_filters.clear();
+        self.job_id_multi_filters.clear();
+        self.oneshot_kind_filters.clear();
+        self.kind_filters.clear();
+        self.notification_filters.clear();
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn emit(&self, event: ConnectionEvent) {
+        debug!(event = ?event, "connection event");
+        self.events.send(event).ok();
+    }
+
+    fn emit_logged_off(&self, message: &RawNetMessage) {
+        if let Ok(logged_off) = CMsgClientLoggedOff::parse_from_bytes(&message.data) {
+            let result = EResult::from_result(logged_off.eresult())
+                .err()
+                .unwrap_or(EResult::OK);
+            if let EResult::TryAnotherCM = result {
+                self.emit(ConnectionEvent::ServerRedirect);
+            }
+            self.emit(ConnectionEvent::LoggedOff(result));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_job_waiter_keeps_new_job() {
+        let filter = MessageFilter::default();
+        let stale = filter.on_job_id(JobId(1));
+        filter.fail_jobs();
+        let current = filter.on_job_id(JobId(1));
+        drop(stale);
+        assert_eq!(filter.outstanding_jobs(), 1);
+        drop(current);
+        assert_eq!(filter.outstanding_jobs(), 0);
+    }
+
+    #[test]
+    fn stale_job_multi_receiver_keeps_new_job() {
+        let filter = MessageFilter::default();
+        let stale = filter.on_job_id_multi(JobId(1));
+        filter.fail_jobs();
+        let current = filter.on_job_id_multi(JobId(1));
+        drop(stale);
+        assert_eq!(filter.outstanding_jobs(), 1);
+        drop(current);
+        assert_eq!(filter.outstanding_jobs(), 0);
+    }
 }
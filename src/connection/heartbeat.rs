@@ -0,0 +1,152 @@
+use super::{ConnectionEvent, MessageFilter, MessageSender};
+use crate::net::{NetMessageHeader, RawNetMessage};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use steam_vent_proto::enums_clientserver::EMsg;
+use steam_vent_proto::steammessages_clientserver_login::CMsgClientHeartBeat;
+use tokio::time::{sleep, timeout};
+use tokio::{select, spawn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
+
+/// Options for the heartbeats sent to keep a session alive and detect dead connections
+///
+/// By default heartbeats are sent at the interval requested by steam and the connection is never declared dead.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeartbeatOptions {
+    interval: Option<Duration>,
+    request_reply: bool,
+    max_missed: Option<u32>,
+}
+
+impl HeartbeatOptions {
+    /// Send heartbeats at a fixed interval instead of the interval requested by steam
+    pub fn with_interval(self, interval: Duration) -> Self {
+        HeartbeatOptions {
+            interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Ask steam to acknowledge every heartbeat, which allows measuring the round-trip time
+    ///
+    /// This also ensures that an idle connection keeps receiving messages, which is recommended when using
+    /// [`with_max_missed`](Self::with_max_missed).
+    pub fn with_request_reply(self, request_reply: bool) -> Self {
+        HeartbeatOptions {
+            request_reply,
+            ..self
+        }
+    }
+
+    /// Declare the connection dead when no message has been received for this many heartbeat intervals
+    ///
+    /// A dead connection is closed, failing any outstanding jobs and emitting [`ConnectionEvent::HeartbeatTimeout`]
+    /// followed by [`ConnectionEvent::TransportClosed`].
+    pub fn with_max_missed(self, max_missed: u32) -> Self {
+        HeartbeatOptions {
+            max_missed: Some(max_missed.max(1)),
+            ..self
+        }
+    }
+}
+
+/// Round-trip time of the last acknowledged heartbeat
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RoundTripTime(Arc<Mutex<Option<Duration>>>);
+
+impl RoundTripTime {
+    pub fn get(&self) -> Option<Duration> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, rtt: Duration) {
+        *self.0.lock().unwrap() = Some(rtt);
+    }
+}
+
+/// The state needed by the heartbeat task of a connection
+pub(crate) struct Heartbeat {
+    pub sender: MessageSender,
+    pub filter: MessageFilter,
+    pub header: NetMessageHeader,
+    /// The interval requested by steam
+    pub interval: Duration,
+    pub options: HeartbeatOptions,
+    pub rtt: RoundTripTime,
+    /// Cancelled once the connection is considered dead
+    pub disconnect: CancellationToken,
+}
+
+impl Heartbeat {
+    /// Send heartbeats until the token is cancelled or the connection is considered dead
+    pub async fn run(self, token: CancellationToken) {
+        let Heartbeat {
+            sender,
+            filter,
+            header,
+            interval,
+            options,
+            rtt,
+            disconnect,
+        } = self;
+        let interval = options.interval.unwrap_or(interval);
+        let started = Instant::now();
+        loop {
+            select! {
+                _ = sleep(interval) => {},
+                _ = token.cancelled() => {
+                    break
+                }
+            };
+
+            if let Some(max_missed) = options.max_missed {
+                let silent_for = filter.last_received().max(started).elapsed();
+                if silent_for > interval * max_missed {
+                    warn!(
+                        ?silent_for,
+                        "no messages received from the server, closing connection"
+                    );
+                    filter.emit(ConnectionEvent::HeartbeatTimeout);
+                    filter.fail_jobs();
+                    disconnect.cancel();
+                    break;
+                }
+            }
+
+            debug!("Sending heartbeat message");
+            let heartbeat = CMsgClientHeartBeat {
+                send_reply: options.request_reply.then_some(true),
+                ..CMsgClientHeartBeat::default()
+            };
+            let msg = match RawNetMessage::from_message(header.clone(), heartbeat) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!(error = ?e, "Failed to prepare heartbeat message");
+                    continue;
+                }
+            };
+            let reply = options
+                .request_reply
+                .then(|| filter.one_kind(EMsg::k_EMsgClientHeartBeat));
+            let sent_at = Instant::now();
+            if let Err(e) = sender.send_raw(msg).await {
+                error!(error = ?e, "Failed to send heartbeat message");
+                filter.emit(ConnectionEvent::HeartbeatMissed);
+                continue;
+            }
+            if let Some(reply) = reply {
+                // wait for the reply separately, so a slow reply doesn't delay the next heartbeat
+                let rtt = rtt.clone();
+                spawn(async move {
+                    if let Ok(Ok(_)) = timeout(interval, reply).await {
+                        let elapsed = sent_at.elapsed();
+                        debug!(rtt = ?elapsed, "heartbeat acknowledged");
+                        rtt.set(elapsed);
+                    }
+                });
+            }
+        }
+        debug!("Heartbeat task stopping");
+    }
+}
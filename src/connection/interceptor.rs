@@ -0,0 +1,63 @@
+use crate::net::RawNetMessage;
+use std::sync::{Arc, RwLock};
+
+/// Hook into the messages sent to and received from steam
+///
+/// Interceptors are called in the order they were added to a connection, with the message returned
+/// by one interceptor being passed to the next.
+///
+/// Note that the header of a [`RawNetMessage`] is already encoded, to change the header of an outgoing message
+/// a new message has to be created.
+pub trait Interceptor: Send + Sync + 'static {
+    /// Called for every message before it is sent
+    fn outgoing(&self, message: RawNetMessage) -> OutgoingAction {
+        OutgoingAction::Send(message)
+    }
+
+    /// Called for every received message before it is passed to any listeners, returning `None` drops the message
+    fn incoming(&self, message: RawNetMessage) -> Option<RawNetMessage> {
+        Some(message)
+    }
+}
+
+/// What to do with an outgoing message after it passed an [`Interceptor`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OutgoingAction {
+    /// Send the (possibly modified) message
+    Send(RawNetMessage),
+    /// Don't send the message
+    Drop,
+    /// Don't send the message, instead pass the response to the listeners as if it was received from steam
+    Respond(RawNetMessage),
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(Arc<RwLock<Vec<Arc<dyn Interceptor>>>>);
+
+impl Interceptors {
+    pub fn push(&self, interceptor: Arc<dyn Interceptor>) {
+        self.0.write().unwrap().push(interceptor);
+    }
+
+    pub fn outgoing(&self, mut message: RawNetMessage) -> OutgoingAction {
+        // clone the list so interceptors can't deadlock by adding interceptors
+        let interceptors = self.0.read().unwrap().clone();
+        for interceptor in interceptors {
+            match interceptor.outgoing(message) {
+                OutgoingAction::Send(next) => message = next,
+                action => return action,
+            }
+        }
+        OutgoingAction::Send(message)
+    }
+
+    pub fn incoming(&self, message: RawNetMessage) -> Option<RawNetMessage> {
+        let interceptors = self.0.read().unwrap().clone();
+        interceptors
+            .into_iter()
+            .try_fold(message, |message, interceptor| {
+                interceptor.incoming(message)
+            })
+    }
+}
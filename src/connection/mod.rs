@@ -1,47 +1,107 @@
+mod call;
+mod event;
 mod filter;
+mod heartbeat;
+mod interceptor;
+mod options;
 pub(crate) mod raw;
+mod reconnect;
 pub(crate) mod unauthenticated;
+mod writer;
 
 use crate::auth::{AuthConfirmationHandler, GuardDataStore};
 use crate::message::{
-    EncodableMessage, NetMessage, ServiceMethodMessage, ServiceMethodResponseMessage,
+    EncodableMessage, EncodedBody, NetMessage, ServiceMethodMessage, ServiceMethodResponseMessage,
 };
 use crate::net::{NetMessageHeader, NetworkError, RawNetMessage};
+use crate::rate_limit::{RateLimitKey, RateLimiter};
+use crate::retry::RetryPolicy;
 use crate::serverlist::ServerList;
 use crate::service_method::ServiceMethodRequest;
 use crate::session::{ConnectionError, Session};
 use async_stream::try_stream;
-pub(crate) use filter::MessageFilter;
-use futures_util::{FutureExt, Sink, SinkExt};
+pub use call::{CallOptions, ServiceMethodCall};
+pub use event::ConnectionEvent;
+pub use filter::ListenerCapacities;
+pub(crate) use filter::{MessageFilter, PendingMessage};
+use futures_util::{FutureExt, Sink};
+pub use heartbeat::HeartbeatOptions;
+pub use interceptor::{Interceptor, OutgoingAction};
+pub use options::ConnectionOptions;
 use raw::RawConnection;
+pub use reconnect::{ReconnectEvent, ReconnectOptions, ReconnectingConnection};
 use std::fmt::{Debug, Formatter};
 use std::future::Future;
 use std::net::IpAddr;
+use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
+use steam_vent_proto::steammessages_clientserver_login::{CMsgClientLogOff, CMsgClientLoggedOff};
 use steam_vent_proto::{JobMultiple, MsgKindEnum};
 use steamid_ng::SteamID;
-use tokio::sync::Mutex;
-use tokio::time::timeout;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep, timeout};
 use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::{Stream, StreamExt};
-use tracing::instrument;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument};
 pub use unauthenticated::UnAuthenticatedConnection;
+pub use writer::BatchOptions;
+use writer::{spawn_writer, WriteCommand};
 
 pub(crate) type Result<T, E = NetworkError> = std::result::Result<T, E>;
 
-type TransportWriter = Arc<Mutex<dyn Sink<RawNetMessage, Error = NetworkError> + Unpin + Send>>;
-
 /// Send raw messages to steam
 #[derive(Clone)]
 pub(crate) struct MessageSender {
-    write: TransportWriter,
+    queue: mpsc::Sender<WriteCommand>,
+    closing: CancellationToken,
+    filter: MessageFilter,
 }
 
 impl MessageSender {
+    pub fn new<S: Sink<RawNetMessage, Error = NetworkError> + Send + 'static>(
+        sink: S,
+        batch_options: BatchOptions,
+        closing: CancellationToken,
+        filter: MessageFilter,
+    ) -> Self {
+        MessageSender {
+            queue: spawn_writer(sink, batch_options),
+            closing,
+            filter,
+        }
+    }
+
+    /// Queue a message to be sent, waiting until it has been written to the transport
     pub async fn send_raw(&self, raw_message: RawNetMessage) -> Result<()> {
-        self.write.lock().await.send(raw_message).await?;
-        Ok(())
+        if self.closing.is_cancelled() {
+            return Err(NetworkError::ConnectionClosed);
+        }
+        let Some(raw_message) = self.filter.intercept_outgoing(raw_message) else {
+            return Ok(());
+        };
+        let (tx, rx) = oneshot::channel();
+        self.queue
+            .send(WriteCommand::Send(Box::new(raw_message), tx))
+            .await
+            .map_err(|_| NetworkError::ConnectionClosed)?;
+        rx.await.map_err(|_| NetworkError::ConnectionClosed)?
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closing.is_cancelled()
+    }
+
+    /// Stop accepting new messages and close the transport once the queued messages are written
+    pub async fn close(&self) -> Result<()> {
+        self.closing.cancel();
+        let (tx, rx) = oneshot::channel();
+        if self.queue.send(WriteCommand::Close(tx)).await.is_err() {
+            // the writer already stopped
+            return Ok(());
+        }
+        rx.await.unwrap_or(Ok(()))
     }
 }
 
@@ -111,6 +171,25 @@ impl Connection {
         self.0.timeout = timeout;
     }
 
+    /// Set the retry policy for rpc-requests and jobs, by default failed requests are not retried
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.0.retry_policy = retry_policy;
+    }
+
+    /// Set the rate limiter for messages and rpc-requests send over this connection
+    ///
+    /// The same limiter can be set on multiple connections to share the limits between them.
+    pub fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.0.rate_limiter = Some(rate_limiter);
+    }
+
+    /// The round-trip time of the last acknowledged heartbeat
+    ///
+    /// This is only measured when heartbeat replies are requested with [`HeartbeatOptions::with_request_reply`].
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.0.round_trip_time.get()
+    }
+
     pub(crate) fn sender(&self) -> &MessageSender {
         &self.0.sender
     }
@@ -122,12 +201,46 @@ impl Connection {
     pub fn take_unprocessed(&self) -> Vec<RawNetMessage> {
         self.0.filter.unprocessed()
     }
+
+    /// Log off from steam and close the connection
+    ///
+    /// Any requests still waiting for a response will fail with [`NetworkError::ConnectionClosed`]
+    pub async fn logoff(&self) -> Result<()> {
+        let logged_off = self.one::<CMsgClientLoggedOff>();
+        self.send(CMsgClientLogOff::default()).await?;
+        if timeout(self.timeout(), logged_off).await.is_err() {
+            debug!("no logoff confirmation received, closing anyway");
+        }
+        self.close().await
+    }
+
+    /// Close the connection without logging off
+    ///
+    /// Any requests still waiting for a response will fail with [`NetworkError::ConnectionClosed`]
+    pub async fn close(&self) -> Result<()> {
+        self.0.close().await
+    }
+
+    /// Listen for changes in the state of the connection, like steam logging us off or the connection being closed
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> + 'static {
+        BroadcastStream::new(self.0.filter.events()).filter_map(|res| res.ok())
+    }
 }
 
 pub(crate) trait ConnectionImpl: Sync + Debug {
     fn timeout(&self) -> Duration;
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::default()
+    }
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        None
+    }
+    /// Whether the connection can still be used to send messages
+    fn connected(&self) -> bool {
+        true
+    }
     fn filter(&self) -> &MessageFilter;
-    fn session(&self) -> &Session;
+    fn session(&self) -> impl Deref<Target = Session> + '_;
 
     fn raw_send_with_kind<Msg: EncodableMessage, K: MsgKindEnum>(
         &self,
@@ -150,6 +263,12 @@ pub trait ReadonlyConnection {
     /// Wait for one message of a specific kind
     fn one<T: NetMessage + 'static>(&self) -> impl Future<Output = Result<T>> + 'static;
 
+    /// Wait for one message of a specific kind for which the predicate returns `true`
+    fn one_matching<T: NetMessage + 'static>(
+        &self,
+        predicate: impl Fn(&NetMessageHeader, &T) -> bool + Send + Sync + 'static,
+    ) -> impl Future<Output = Result<T>> + 'static;
+
     /// Listen to messages of a specific kind, also returning the header
     fn on_with_header<T: NetMessage + 'static>(
         &self,
@@ -171,6 +290,12 @@ pub trait ConnectionTrait {
     /// Wait for one message of a specific kind
     fn one<T: NetMessage + 'static>(&self) -> impl Future<Output = Result<T>> + 'static;
 
+    /// Wait for one message of a specific kind for which the predicate returns `true`
+    fn one_matching<T: NetMessage + 'static>(
+        &self,
+        predicate: impl Fn(&NetMessageHeader, &T) -> bool + Send + Sync + 'static,
+    ) -> impl Future<Output = Result<T>> + 'static;
+
     /// Listen to messages of a specific kind, also returning the header
     fn on_with_header<T: NetMessage + 'static>(
         &self,
@@ -185,12 +310,40 @@ pub trait ConnectionTrait {
         msg: Msg,
     ) -> impl Future<Output = Result<Msg::Response>> + Send;
 
+    /// Send a rpc-request to steam, waiting for the matching rpc-response, overriding the timeout and retry policy of the connection
+    fn service_method_with_options<Msg: ServiceMethodRequest>(
+        &self,
+        msg: Msg,
+        options: CallOptions,
+    ) -> impl Future<Output = Result<Msg::Response>> + Send;
+
+    /// Prepare a rpc-request to steam, allowing per-call options to be set before sending it
+    fn call<Msg: ServiceMethodRequest>(&self, msg: Msg) -> ServiceMethodCall<'_, Self, Msg>
+    where
+        Self: Sized;
+
     /// Send a message to steam, waiting for a response with the same job id
     fn job<Msg: NetMessage, Rsp: NetMessage>(
         &self,
         msg: Msg,
     ) -> impl Future<Output = Result<Rsp>> + Send;
 
+    /// Send a message to steam, waiting for a response with the same job id, overriding the timeout and retry policy of the connection
+    fn job_with_options<Msg: NetMessage, Rsp: NetMessage>(
+        &self,
+        msg: Msg,
+        options: CallOptions,
+    ) -> impl Future<Output = Result<Rsp>> + Send;
+
+    /// The number of jobs and rpc-requests that are still waiting for a response
+    fn outstanding_jobs(&self) -> usize;
+
+    /// Whether the connection is still open
+    fn is_connected(&self) -> bool;
+
+    /// Add an interceptor that sees all messages sent and received by this connection
+    fn add_interceptor(&self, interceptor: impl Interceptor);
+
     /// Send a message to steam, receiving responses until the response marks that the response is complete
     fn job_multi<Msg: NetMessage, Rsp: NetMessage + JobMultiple>(
         &self,
@@ -227,11 +380,23 @@ impl ConnectionImpl for Connection {
         self.0.timeout()
     }
 
+    fn retry_policy(&self) -> RetryPolicy {
+        self.0.retry_policy()
+    }
+
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.0.rate_limiter()
+    }
+
+    fn connected(&self) -> bool {
+        self.0.connected()
+    }
+
     fn filter(&self) -> &MessageFilter {
         self.0.filter()
     }
 
-    fn session(&self) -> &Session {
+    fn session(&self) -> impl Deref<Target = Session> + '_ {
         self.0.session()
     }
 
@@ -253,11 +418,69 @@ impl ConnectionImpl for Connection {
     }
 }
 
+/// Send a message with a new job id and wait for the response, retrying according to the retry policy
+///
+/// Every attempt waits for the rate limiter of the connection, if one is set.
+async fn send_job<C: ConnectionImpl, Msg: NetMessage, Rsp: NetMessage, T>(
+    connection: &C,
+    msg: Msg,
+    options: CallOptions,
+    limit_key: RateLimitKey,
+    into_response: impl Fn(Rsp) -> Result<T>,
+) -> Result<T> {
+    let timeout_duration = options.timeout.unwrap_or_else(|| connection.timeout());
+    let retry = options.retry.unwrap_or_else(|| connection.retry_policy());
+    let rate_limiter = connection.rate_limiter();
+    let mut header = connection.session().header(true);
+    msg.process_header(&mut header);
+    // encode the body once so it can be re-sent without requiring the message to be `Clone`
+    let body = EncodedBody::encode(&msg)?;
+    let mut attempt = 0;
+    loop {
+        if let Some(rate_limiter) = &rate_limiter {
+            rate_limiter.acquire(limit_key).await;
+        }
+        let recv = connection.filter().on_job_id(header.source_job_id);
+        let sent = connection
+            .raw_send_with_kind(header.clone(), body.clone(), Msg::KIND, Msg::IS_PROTOBUF)
+            .await;
+        let raw = match sent {
+            Ok(()) => match timeout(timeout_duration, recv).await {
+                Ok(Ok(raw)) => Ok(raw),
+                Ok(Err(_)) => Err(NetworkError::ConnectionClosed),
+                Err(_) => Err(NetworkError::Timeout),
+            },
+            Err(e) => Err(e),
+        };
+        // only decode once all awaits for this attempt are done, the response type isn't required to be `Send`
+        let error = {
+            let result = raw
+                .and_then(RawNetMessage::into_message::<Rsp>)
+                .and_then(&into_response);
+            let result = match limit_key {
+                RateLimitKey::Method(method) => result.map_err(|e| e.for_method(method)),
+                RateLimitKey::Kind(..) => result,
+            };
+            if let Some(rate_limiter) = &rate_limiter {
+                rate_limiter.record(limit_key, &result);
+            }
+            match result {
+                Err(e) if retry.should_retry(&e, attempt) => e,
+                result => return result,
+            }
+        };
+        let delay = retry.delay(attempt);
+        debug!(error = ?error, attempt, ?delay, "retrying job");
+        sleep(delay).await;
+        attempt += 1;
+        header.source_job_id = connection.session().job_id.next();
+    }
+}
+
 impl<C: ConnectionImpl> ConnectionTrait for C {
     fn on_notification<T: ServiceMethodRequest>(&self) -> impl Stream<Item = Result<T>> + 'static {
         BroadcastStream::new(self.filter().on_notification(T::REQ_NAME))
-            .filter_map(|res| res.ok())
-            .map(|raw| raw.into_notification())
+            .map(|raw| raw.map_err(NetworkError::from)?.into_notification())
     }
 
     fn one_with_header<T: NetMessage + 'static>(
@@ -267,7 +490,7 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
         // to the lifetime of &self
         let fut = self.filter().one_kind(T::KIND);
         async move {
-            let raw = fut.await.map_err(|_| NetworkError::EOF)?;
+            let raw = fut.await.map_err(|_| NetworkError::ConnectionClosed)?;
             raw.into_header_and_message()
         }
     }
@@ -277,11 +500,29 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
             .map(|res| res.map(|(_, msg)| msg))
     }
 
+    fn one_matching<T: NetMessage + 'static>(
+        &self,
+        predicate: impl Fn(&NetMessageHeader, &T) -> bool + Send + Sync + 'static,
+    ) -> impl Future<Output = Result<T>> + 'static {
+        let fut = self.filter().one_kind_matching(
+            T::KIND,
+            Some(Box::new(move |message: &mut PendingMessage| {
+                message
+                    .decode::<T>()
+                    .is_some_and(|(header, msg)| predicate(header, msg))
+            })),
+        );
+        async move {
+            let raw = fut.await.map_err(|_| NetworkError::ConnectionClosed)?;
+            raw.into_message()
+        }
+    }
+
     fn on_with_header<T: NetMessage + 'static>(
         &self,
     ) -> impl Stream<Item = Result<(NetMessageHeader, T)>> + 'static {
         BroadcastStream::new(self.filter().on_kind(T::KIND)).map(|raw| {
-            let raw = raw.map_err(|_| NetworkError::EOF)?;
+            let raw = raw?;
             raw.into_header_and_message()
         })
     }
@@ -292,26 +533,41 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
     }
 
     async fn service_method<Msg: ServiceMethodRequest>(&self, msg: Msg) -> Result<Msg::Response> {
-        let header = self.session().header(true);
-        let recv = self.filter().on_job_id(header.source_job_id);
-        self.raw_send(header, ServiceMethodMessage(msg)).await?;
-        let message = timeout(self.timeout(), recv)
+        self.service_method_with_options(msg, CallOptions::default())
             .await
-            .map_err(|_| NetworkError::Timeout)?
-            .map_err(|_| NetworkError::EOF)?
-            .into_message::<ServiceMethodResponseMessage>()?;
-        message.into_response::<Msg>()
+    }
+
+    async fn service_method_with_options<Msg: ServiceMethodRequest>(
+        &self,
+        msg: Msg,
+        options: CallOptions,
+    ) -> Result<Msg::Response> {
+        let limit_key = RateLimitKey::Method(Msg::REQ_NAME);
+        send_job(
+            self,
+            ServiceMethodMessage(msg),
+            options,
+            limit_key,
+            ServiceMethodResponseMessage::into_response::<Msg>,
+        )
+        .await
+    }
+
+    fn call<Msg: ServiceMethodRequest>(&self, msg: Msg) -> ServiceMethodCall<'_, Self, Msg> {
+        ServiceMethodCall::new(self, msg)
     }
 
     async fn job<Msg: NetMessage, Rsp: NetMessage>(&self, msg: Msg) -> Result<Rsp> {
-        let header = self.session().header(true);
-        let recv = self.filter().on_job_id(header.source_job_id);
-        self.raw_send(header, msg).await?;
-        timeout(self.timeout(), recv)
-            .await
-            .map_err(|_| NetworkError::Timeout)?
-            .map_err(|_| NetworkError::EOF)?
-            .into_message()
+        self.job_with_options(msg, CallOptions::default()).await
+    }
+
+    async fn job_with_options<Msg: NetMessage, Rsp: NetMessage>(
+        &self,
+        msg: Msg,
+        options: CallOptions,
+    ) -> Result<Rsp> {
+        let limit_key = RateLimitKey::kind(Msg::KIND);
+        send_job(self, msg, options, limit_key, Ok).await
     }
 
     fn job_multi<Msg: NetMessage, Rsp: NetMessage + JobMultiple>(
@@ -327,7 +583,7 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
                 let msg: Rsp = timeout(self.timeout(), recv.recv())
                     .await
                     .map_err(|_| NetworkError::Timeout)?
-                    .ok_or(NetworkError::EOF)?
+                    .ok_or(NetworkError::ConnectionClosed)??
                     .into_message()?;
                 let completed = msg.completed();
                 yield msg;
@@ -339,6 +595,18 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
         }
     }
 
+    fn outstanding_jobs(&self) -> usize {
+        self.filter().outstanding_jobs()
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected()
+    }
+
+    fn add_interceptor(&self, interceptor: impl Interceptor) {
+        self.filter().add_interceptor(Arc::new(interceptor));
+    }
+
     #[instrument(skip(msg), fields(kind = ?Msg::KIND))]
     fn send<Msg: NetMessage>(&self, msg: Msg) -> impl Future<Output = Result<()>> + Send {
         self.raw_send(self.session().header(false), msg)
@@ -369,6 +637,15 @@ impl<C: ConnectionImpl> ConnectionTrait for C {
         kind: K,
         is_protobuf: bool,
     ) -> impl Future<Output = Result<()>> + Send {
-        <Self as ConnectionImpl>::raw_send_with_kind(self, header, msg, kind, is_protobuf)
+        let rate_limiter = self.rate_limiter();
+        let limit_key = RateLimitKey::kind(kind);
+        let send =
+            <Self as ConnectionImpl>::raw_send_with_kind(self, header, msg, kind, is_protobuf);
+        async move {
+            if let Some(rate_limiter) = rate_limiter {
+                rate_limiter.acquire(limit_key).await;
+            }
+            send.await
+        }
     }
 }
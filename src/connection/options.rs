@@ -0,0 +1,73 @@
+use super::{BatchOptions, HeartbeatOptions, ListenerCapacities};
+use crate::capture::Recorder;
+use crate::net::ParseLimits;
+
+/// Options for a connection that don't depend on the transport used
+///
+/// The transport itself is configured with [`TransportConfig`](crate::TransportConfig).
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionOptions {
+    listener_capacities: ListenerCapacities,
+    heartbeat: HeartbeatOptions,
+    batching: BatchOptions,
+    parse_limits: ParseLimits,
+    recorder: Option<Recorder>,
+}
+
+impl ConnectionOptions {
+    /// Set the buffer sizes for the listeners of incoming messages
+    pub fn with_listener_capacities(self, listener_capacities: ListenerCapacities) -> Self {
+        ConnectionOptions {
+            listener_capacities,
+            ..self
+        }
+    }
+
+    /// Set how heartbeats are sent and when a connection that stopped responding is considered dead
+    pub fn with_heartbeat(self, heartbeat: HeartbeatOptions) -> Self {
+        ConnectionOptions { heartbeat, ..self }
+    }
+
+    /// Set how outgoing messages that are queued at the same time are combined before being written
+    pub fn with_batching(self, batching: BatchOptions) -> Self {
+        ConnectionOptions { batching, ..self }
+    }
+
+    /// Set the limits for parsing incoming messages
+    pub fn with_parse_limits(self, parse_limits: ParseLimits) -> Self {
+        ConnectionOptions {
+            parse_limits,
+            ..self
+        }
+    }
+
+    /// Record all messages sent and received by the connection
+    ///
+    /// See [`Recorder`] for which credentials end up in the capture.
+    pub fn with_recorder(self, recorder: Recorder) -> Self {
+        ConnectionOptions {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    pub(crate) fn listener_capacities(&self) -> ListenerCapacities {
+        self.listener_capacities
+    }
+
+    pub(crate) fn heartbeat(&self) -> HeartbeatOptions {
+        self.heartbeat
+    }
+
+    pub(crate) fn batching(&self) -> BatchOptions {
+        self.batching
+    }
+
+    pub(crate) fn parse_limits(&self) -> ParseLimits {
+        self.parse_limits
+    }
+
+    pub(crate) fn recorder(&self) -> Option<Recorder> {
+        self.recorder.clone()
+    }
+}
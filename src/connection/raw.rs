@@ -1,31 +1,50 @@
 use super::Result;
-use crate::connection::{ConnectionImpl, MessageFilter, MessageSender};
+use crate::capture::record_transport;
+use crate::connection::heartbeat::{Heartbeat, RoundTripTime};
+use crate::connection::{ConnectionImpl, ConnectionOptions, MessageFilter, MessageSender};
 use crate::message::{flatten_multi, EncodableMessage};
 use crate::net::{NetMessageHeader, RawNetMessage};
-use crate::session::{hello, Session};
-use crate::transport::websocket::connect;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::serverlist::{ws_url, CmServer};
+use crate::session::{hello, Credentials, Session};
+use crate::transport::{connect_parallel, tcp, websocket, Transport, TransportConfig};
 use crate::{ConnectionError, NetworkError, ServerList};
 use bytes::BytesMut;
-use futures_util::{Sink, SinkExt, Stream};
+use futures_util::{Sink, SinkExt, Stream, TryStreamExt};
 use std::fmt::{Debug, Formatter};
 use std::future::ready;
+use std::ops::Deref;
+use std::pin::pin;
 use std::sync::Arc;
 use std::time::Duration;
-use steam_vent_proto::steammessages_clientserver_login::CMsgClientHeartBeat;
+use steam_vent_proto::enums_clientserver::EMsg;
+use steam_vent_proto::steammessages_clientserver::CMsgClientCMList;
 use steam_vent_proto::MsgKindEnum;
-use tokio::sync::Mutex;
-use tokio::time::sleep;
-use tokio::{select, spawn};
+use tokio::spawn;
+use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::StreamExt;
-use tokio_util::sync::{CancellationToken, DropGuard};
-use tracing::{debug, error};
+use tokio_util::sync::{CancellationToken, DropGuard, WaitForCancellationFutureOwned};
+use tracing::debug;
 
 #[derive(Clone)]
 pub(crate) struct RawConnection {
     pub session: Session,
     pub filter: MessageFilter,
     pub timeout: Duration,
+    pub retry_policy: RetryPolicy,
+    pub rate_limiter: Option<RateLimiter>,
     pub sender: MessageSender,
+    pub credentials: Option<Arc<Credentials>>,
+    pub transport_config: TransportConfig,
+    pub options: ConnectionOptions,
+    /// The server list the connection was made from and the server that was picked from it
+    pub server: Option<(ServerList, CmServer)>,
+    pub round_trip_time: RoundTripTime,
+    closed: CancellationToken,
+    closing: CancellationToken,
+    disconnect: CancellationToken,
+    detach: CancellationToken,
     heartbeat_cancellation_token: CancellationToken,
     _heartbeat_drop_guard: Arc<DropGuard>,
 }
@@ -37,9 +56,61 @@ impl Debug for RawConnection {
 }
 
 impl RawConnection {
-    pub async fn connect(server_list: &ServerList) -> Result<Self, ConnectionError> {
-        let (sender, receiver) = connect(&server_list.pick_ws()).await?;
-        Self::from_sender_receiver(sender, receiver).await
+    pub async fn connect(
+        server_list: &ServerList,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
+    ) -> Result<Self, ConnectionError> {
+        let filter = MessageFilter::with_capacities(options.listener_capacities());
+        Self::connect_with_filter(server_list, config, options, filter).await
+    }
+
+    /// Connect to a server, dispatching incoming messages to the listeners of an existing filter
+    pub async fn connect_with_filter(
+        server_list: &ServerList,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
+        filter: MessageFilter,
+    ) -> Result<Self, ConnectionError> {
+        let (mut connection, server) = match config.transport() {
+            Transport::WebSocket => {
+                let (addr, (sender, receiver)) = config
+                    .with_deadline(connect_parallel(
+                        server_list.pick_many_ws(config.connect_candidates()),
+                        config.attempt_delay(),
+                        |addr| async move {
+                            websocket::connect(&ws_url(&addr), config, options.parse_limits()).await
+                        },
+                        |addr| server_list.cool_down_ws(addr),
+                    ))
+                    .await?;
+                (
+                    Self::from_sender_receiver_with_filter(
+                        sender, receiver, filter, config, options,
+                    )
+                    .await?,
+                    CmServer::WebSocket(addr),
+                )
+            }
+            Transport::Tcp => {
+                let (addr, (sender, receiver)) = config
+                    .with_deadline(connect_parallel(
+                        server_list.pick_many(config.connect_candidates()),
+                        config.attempt_delay(),
+                        |addr| tcp::connect(addr, config, options.parse_limits()),
+                        |addr| server_list.cool_down(*addr),
+                    ))
+                    .await?;
+                (
+                    Self::from_raw_sender_receiver(sender, receiver, filter, config, options)
+                        .await?,
+                    CmServer::Tcp(addr),
+                )
+            }
+        };
+        connection.watch_cm_list(server_list.clone());
+        connection.server = Some((server_list.clone(), server));
+        Ok(connection)
     }
 
     pub async fn from_sender_receiver<
@@ -48,19 +119,75 @@ impl RawConnection {
     >(
         sender: Sender,
         receiver: Receiver,
+        options: &ConnectionOptions,
+    ) -> Result<Self, ConnectionError> {
+        Self::from_sender_receiver_with_filter(
+            sender,
+            receiver,
+            MessageFilter::with_capacities(options.listener_capacities()),
+            &TransportConfig::default(),
+            options,
+        )
+        .await
+    }
+
+    pub async fn from_sender_receiver_with_filter<
+        Sender: Sink<BytesMut, Error = NetworkError> + Send + 'static,
+        Receiver: Stream<Item = Result<BytesMut>> + Send + 'static,
+    >(
+        sender: Sender,
+        receiver: Receiver,
+        filter: MessageFilter,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
     ) -> Result<Self, ConnectionError> {
         let sender = sender.with(|msg: RawNetMessage| ready(Ok(msg.into_bytes())));
-        let receiver = flatten_multi(receiver.map(|res| res.and_then(RawNetMessage::read)));
+        let limits = options.parse_limits();
+        let receiver = flatten_multi(
+            receiver.and_then(move |raw| ready(RawNetMessage::read_with_limits(raw, &limits))),
+            limits,
+        );
+        Self::from_raw_sender_receiver(sender, receiver, filter, config, options).await
+    }
 
-        let filter = MessageFilter::new(receiver);
+    /// Create a connection from a transport that handles the message encoding itself
+    ///
+    /// Any multi messages need to already be flattened by the transport.
+    pub async fn from_raw_sender_receiver<
+        Sender: Sink<RawNetMessage, Error = NetworkError> + Send + 'static,
+        Receiver: Stream<Item = Result<RawNetMessage>> + Send + 'static,
+    >(
+        sender: Sender,
+        receiver: Receiver,
+        filter: MessageFilter,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
+    ) -> Result<Self, ConnectionError> {
+        let (sender, receiver) = record_transport(sender, receiver, options.recorder());
+        let closing = CancellationToken::new();
+        // stops the receiver when we close the connection, or when the connection is considered dead
+        let disconnect = closing.child_token();
+        let receiver =
+            futures_util::StreamExt::take_until(receiver, disconnect.clone().cancelled_owned());
+        let detach = CancellationToken::new();
+        let closed = filter.attach(receiver, detach.clone());
         let heartbeat_cancellation_token = CancellationToken::new();
         let mut connection = RawConnection {
             session: Session::default(),
+            sender: MessageSender::new(sender, options.batching(), closing.clone(), filter.clone()),
             filter,
-            sender: MessageSender {
-                write: Arc::new(Mutex::new(Box::pin(sender))),
-            },
             timeout: Duration::from_secs(10),
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            credentials: None,
+            transport_config: config.clone(),
+            options: options.clone(),
+            server: None,
+            round_trip_time: RoundTripTime::default(),
+            closed,
+            closing,
+            disconnect,
+            detach,
             heartbeat_cancellation_token: heartbeat_cancellation_token.clone(),
             // We just store a drop guard using an `Arc` here, so dropping the last clone of `Connection` will cancel the heartbeat task.
             _heartbeat_drop_guard: Arc::new(heartbeat_cancellation_token.drop_guard()),
@@ -69,8 +196,83 @@ impl RawConnection {
         Ok(connection)
     }
 
+    /// Wait for the underlying transport to be closed
+    pub fn closed(&self) -> WaitForCancellationFutureOwned {
+        self.closed.clone().cancelled_owned()
+    }
+
+    /// Token that is cancelled once the connection is closed by us
+    pub fn closing(&self) -> CancellationToken {
+        self.closing.clone()
+    }
+
+    /// Close the transport and fail any request still waiting for a response
+    pub async fn close(&self) -> Result<()> {
+        debug!("closing connection");
+        self.heartbeat_cancellation_token.cancel();
+        let result = self.sender.close().await;
+        self.filter.close();
+        result
+    }
+
+    /// Close the transport without touching the listeners of the filter, so they can be moved to a new connection
+    ///
+    /// The transport stops dispatching messages and events to the filter immediately.
+    pub async fn detach(&self) {
+        debug!("detaching connection");
+        self.heartbeat_cancellation_token.cancel();
+        self.detach.cancel();
+        if let Err(e) = self.sender.close().await {
+            debug!(error = ?e, "error while closing detached connection");
+        }
+    }
+
+    /// Skip the server of this connection for a while, when steam asks us to connect to another server
+    pub fn cool_down_server(&self) {
+        if let Some((server_list, server)) = &self.server {
+            debug!(server = ?server, "redirected to another server");
+            server_list.cool_down_server(server);
+        }
+    }
+
+    /// Replace the connection with a connection to another server from the same server list
+    ///
+    /// Listeners of the filter are kept.
+    pub async fn redirect(self) -> Result<Self, ConnectionError> {
+        let Some((server_list, _)) = &self.server else {
+            return Err(ConnectionError::Aborted);
+        };
+        self.cool_down_server();
+        self.detach().await;
+        Self::connect_with_filter(
+            server_list,
+            &self.transport_config,
+            &self.options,
+            self.filter.clone(),
+        )
+        .await
+    }
+
+    /// Merge server lists pushed by steam into the server list for as long as the connection is open
+    fn watch_cm_list(&self, server_list: ServerList) {
+        let updates = BroadcastStream::new(self.filter.on_kind(EMsg::k_EMsgClientCMList));
+        let updates = futures_util::StreamExt::take_until(updates, self.closed());
+        spawn(async move {
+            let mut updates = pin!(updates);
+            while let Some(update) = updates.next().await {
+                let list = match update {
+                    Ok(raw) => raw.into_message::<CMsgClientCMList>(),
+                    Err(e) => Err(NetworkError::from(e)),
+                };
+                match list {
+                    Ok(list) => server_list.merge_cm_list(&list),
+                    Err(e) => debug!(error = ?e, "failed to read server list update"),
+                }
+            }
+        });
+    }
+
     pub fn setup_heartbeat(&self) {
-        let sender = self.sender.clone();
         let interval = self.session.heartbeat_interval;
         let header = NetMessageHeader {
             session_id: self.session.session_id,
@@ -78,29 +280,16 @@ impl RawConnection {
             ..NetMessageHeader::default()
         };
         debug!("Setting up heartbeat with interval {:?}", interval);
-        let token = self.heartbeat_cancellation_token.clone();
-        spawn(async move {
-            loop {
-                select! {
-                    _ = sleep(interval) => {},
-                    _ = token.cancelled() => {
-                        break
-                    }
-                };
-                debug!("Sending heartbeat message");
-                match RawNetMessage::from_message(header.clone(), CMsgClientHeartBeat::default()) {
-                    Ok(msg) => {
-                        if let Err(e) = sender.send_raw(msg).await {
-                            error!(error = ?e, "Failed to send heartbeat message");
-                        }
-                    }
-                    Err(e) => {
-                        error!(error = ?e, "Failed to prepare heartbeat message")
-                    }
-                }
-            }
-            debug!("Heartbeat task stopping");
-        });
+        let heartbeat = Heartbeat {
+            sender: self.sender.clone(),
+            filter: self.filter.clone(),
+            header,
+            interval,
+            options: self.options.heartbeat(),
+            rtt: self.round_trip_time.clone(),
+            disconnect: self.disconnect.clone(),
+        };
+        spawn(heartbeat.run(self.heartbeat_cancellation_token.clone()));
     }
 }
 
@@ -109,11 +298,23 @@ impl ConnectionImpl for RawConnection {
         self.timeout
     }
 
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    fn connected(&self) -> bool {
+        !self.closed.is_cancelled() && !self.sender.is_closed()
+    }
+
     fn filter(&self) -> &MessageFilter {
         &self.filter
     }
 
-    fn session(&self) -> &Session {
+    fn session(&self) -> impl Deref<Target = Session> + '_ {
         &self.session
     }
 
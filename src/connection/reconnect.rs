@@ -0,0 +1,309 @@
+use super::{ConnectionEvent, ConnectionImpl, MessageFilter, Result, UnAuthenticatedConnection};
+use crate::backoff::Backoff;
+use crate::eresult::EResult;
+use crate::message::EncodableMessage;
+use crate::net::NetMessageHeader;
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::session::{ConnectionError, Session};
+use crate::{Connection, ServerList};
+use futures_util::future::{select, Either};
+use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
+use std::pin::pin;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::Duration;
+use steam_vent_proto::MsgKindEnum;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::sleep;
+use tokio::{select, spawn};
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::{debug, info, warn};
+
+/// Options for [`ReconnectingConnection`]
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectOptions {
+    backoff: Backoff,
+    max_attempts: Option<u32>,
+}
+
+impl ReconnectOptions {
+    /// Set the backoff policy used between reconnect attempts
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        ReconnectOptions { backoff, ..self }
+    }
+
+    /// Give up after a number of failed reconnect attempts, by default reconnecting is retried forever
+    pub fn with_max_attempts(self, max_attempts: u32) -> Self {
+        ReconnectOptions {
+            max_attempts: Some(max_attempts),
+            ..self
+        }
+    }
+}
+
+/// Events emitted by a [`ReconnectingConnection`]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum ReconnectEvent {
+    /// The connection to the server was lost or steam logged us off
+    Disconnected,
+    /// A reconnect attempt will be made after the delay
+    Reconnecting { attempt: u32, delay: Duration },
+    /// A reconnect attempt failed
+    ReconnectFailed {
+        attempt: u32,
+        error: Arc<ConnectionError>,
+    },
+    /// The connection has been re-established and a new session was started
+    Reconnected,
+    /// No more reconnect attempts will be made
+    GaveUp,
+}
+
+/// A connection that automatically reconnects and starts a new session when the connection is lost
+///
+/// After reconnecting, existing listeners created with [`on`](crate::ConnectionTrait::on)
+/// or [`on_notification`](crate::ConnectionTrait::on_notification) keep receiving messages from the new connection.
+#[derive(Clone)]
+pub struct ReconnectingConnection {
+    state: Arc<ReconnectState>,
+    shutdown: CancellationToken,
+    // dropping the last clone stops the reconnect task
+    _shutdown_guard: Arc<DropGuard>,
+}
+
+struct ReconnectState {
+    current: RwLock<Connection>,
+    filter: MessageFilter,
+    server_list: ServerList,
+    options: ReconnectOptions,
+}
+
+impl Debug for ReconnectingConnection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReconnectingConnection")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReconnectingConnection {
+    /// Supervise an existing connection, reconnecting using servers from the `server_list`
+    pub fn new(connection: Connection, server_list: ServerList, options: ReconnectOptions) -> Self {
+        let shutdown = CancellationToken::new();
+        let state = Arc::new(ReconnectState {
+            filter: connection.0.filter.clone(),
+            current: RwLock::new(connection),
+            server_list,
+            options,
+        });
+        spawn(supervise(state.clone(), shutdown.clone()));
+        ReconnectingConnection {
+            state,
+            shutdown: shutdown.clone(),
+            _shutdown_guard: Arc::new(shutdown.drop_guard()),
+        }
+    }
+
+    /// Get the currently active connection
+    pub fn connection(&self) -> Connection {
+        self.state.current.read().unwrap().clone()
+    }
+
+    /// Stop reconnecting, log off from steam and close the connection
+    pub async fn logoff(&self) -> Result<()> {
+        self.shutdown.cancel();
+        self.connection().logoff().await
+    }
+
+    /// Stop reconnecting and close the connection without logging off
+    pub async fn close(&self) -> Result<()> {
+        self.shutdown.cancel();
+        self.connection().close().await
+    }
+
+    /// Listen for changes in the state of the connection, including reconnects
+    pub fn events(&self) -> impl Stream<Item = ConnectionEvent> + 'static {
+        BroadcastStream::new(self.state.filter.events()).filter_map(|res| res.ok())
+    }
+}
+
+struct SessionGuard<'a>(RwLockReadGuard<'a, Connection>);
+
+impl Deref for SessionGuard<'_> {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0 .0.session
+    }
+}
+
+impl ConnectionImpl for ReconnectingConnection {
+    fn timeout(&self) -> Duration {
+        self.state.current.read().unwrap().timeout()
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        self.state.current.read().unwrap().retry_policy()
+    }
+
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.state.current.read().unwrap().rate_limiter()
+    }
+
+    fn connected(&self) -> bool {
+        self.state.current.read().unwrap().connected()
+    }
+
+    fn filter(&self) -> &MessageFilter {
+        &self.state.filter
+    }
+
+    fn session(&self) -> impl Deref<Target = Session> + '_ {
+        SessionGuard(self.state.current.read().unwrap())
+    }
+
+    async fn raw_send_with_kind<Msg: EncodableMessage, K: MsgKindEnum>(
+        &self,
+        header: NetMessageHeader,
+        msg: Msg,
+        kind: K,
+        is_protobuf: bool,
+    ) -> Result<()> {
+        let connection = self.connection();
+        connection
+            .raw_send_with_kind(header, msg, kind, is_protobuf)
+            .await
+    }
+}
+
+async fn supervise(state: Arc<ReconnectState>, shutdown: CancellationToken) {
+    loop {
+        let (closed, closing) = {
+            let current = state.current.read().unwrap();
+            (current.0.closed(), current.0.closing())
+        };
+        let mut events = state.filter.events();
+        let logged_off = async {
+            loop {
+                match events.recv().await {
+                    Ok(ConnectionEvent::LoggedOff(result)) => break Some(result),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break None,
+                }
+            }
+        };
+        let reason = select! {
+            _ = shutdown.cancelled() => return,
+            _ = closed => None,
+            reason = logged_off => reason,
+        };
+
+        state.emit(ReconnectEvent::Disconnected);
+        if closing.is_cancelled() || matches!(reason, Some(EResult::OK)) {
+            debug!("connection was closed by us, not reconnecting");
+            state.emit(ReconnectEvent::GaveUp);
+            return;
+        }
+        if matches!(
+            reason,
+            Some(EResult::LoggedInElsewhere | EResult::LogonSessionReplaced)
+        ) {
+            info!(reason = ?reason, "session was replaced, not reconnecting");
+            state.emit(ReconnectEvent::GaveUp);
+            return;
+        }
+
+        let old = state.current.read().unwrap().clone();
+        if matches!(reason, Some(EResult::TryAnotherCM)) {
+            old.0.cool_down_server();
+        }
+        // make sure the old connection can't deliver anything to the shared filter once the new one is attached
+        old.0.detach().await;
+        // responses to jobs sent over the old connection will never arrive
+        state.filter.fail_jobs();
+
+        let mut attempt = 0;
+        loop {
+            if state
+                .options
+                .max_attempts
+                .is_some_and(|max_attempts| attempt >= max_attempts)
+            {
+                warn!(attempt, "giving up on reconnecting");
+                state.emit(ReconnectEvent::GaveUp);
+                return;
+            }
+            let delay = state.options.backoff.delay(attempt);
+            state.emit(ReconnectEvent::Reconnecting { attempt, delay });
+            debug!(attempt, ?delay, "reconnecting");
+
+            let attempt_reconnect = async {
+                sleep(delay).await;
+                state.reconnect().await
+            };
+            let result = match select(pin!(shutdown.cancelled()), pin!(attempt_reconnect)).await {
+                Either::Left(_) => return,
+                Either::Right((result, _)) => result,
+            };
+
+            match result {
+                Ok(connection) => {
+                    info!(attempt, "reconnected");
+                    *state.current.write().unwrap() = connection;
+                    state.emit(ReconnectEvent::Reconnected);
+                    break;
+                }
+                Err(error) => {
+                    warn!(attempt, error = ?error, "failed to reconnect");
+                    state.emit(ReconnectEvent::ReconnectFailed {
+                        attempt,
+                        error: Arc::new(error),
+                    });
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+impl ReconnectState {
+    fn emit(&self, event: ReconnectEvent) {
+        self.filter.emit(ConnectionEvent::Reconnect(event));
+    }
+
+    async fn reconnect(&self) -> Result<Connection, ConnectionError> {
+        let (credentials, transport_config, options, timeout, retry_policy, rate_limiter, job_id) = {
+            let current = self.current.read().unwrap();
+            (
+                current.0.credentials.clone(),
+                current.0.transport_config.clone(),
+                current.0.options.clone(),
+                current.0.timeout,
+                current.0.retry_policy.clone(),
+                current.0.rate_limiter.clone(),
+                current.0.session.job_id.clone(),
+            )
+        };
+        let credentials = credentials.ok_or(ConnectionError::Aborted)?;
+        let mut connection = UnAuthenticatedConnection::connect_with_filter(
+            &self.server_list,
+            &transport_config,
+            &options,
+            self.filter.clone(),
+        )
+        .await?
+        .logon(credentials.as_ref().clone())
+        .await?;
+        // keep counting job ids where the old session left off, so late drops of old jobs can't collide with new ones
+        connection.0.session.job_id = job_id;
+        connection.set_timeout(timeout);
+        connection.set_retry_policy(retry_policy);
+        if let Some(rate_limiter) = rate_limiter {
+            connection.set_rate_limiter(rate_limiter);
+        }
+        Ok(connection)
+    }
+}
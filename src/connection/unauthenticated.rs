@@ -1,17 +1,20 @@
 use super::raw::RawConnection;
 use super::{ReadonlyConnection, Result};
 use crate::auth::{begin_password_auth, AuthConfirmationHandler, GuardDataStore};
+use crate::connection::{ConnectionOptions, MessageFilter, PendingMessage};
 use crate::message::{ServiceMethodMessage, ServiceMethodResponseMessage};
 use crate::net::{NetMessageHeader, RawNetMessage};
 use crate::service_method::ServiceMethodRequest;
-use crate::session::{anonymous, login};
-use crate::{Connection, ConnectionError, NetMessage, NetworkError, ServerList};
+use crate::session::{logon, Credentials};
+use crate::transport::{Transport, TransportConfig};
+use crate::{Connection, ConnectionError, LoginError, NetMessage, NetworkError, ServerList};
 use bytes::BytesMut;
 use futures_util::future::{select, Either};
 use futures_util::Stream;
 use futures_util::{FutureExt, Sink};
 use std::future::Future;
 use std::pin::pin;
+use std::sync::Arc;
 use steam_vent_proto::enums_clientserver::EMsg;
 use steamid_ng::{AccountType, SteamID};
 use tokio::time::timeout;
@@ -19,6 +22,9 @@ use tokio_stream::wrappers::BroadcastStream;
 use tokio_stream::StreamExt;
 use tracing::{debug, error};
 
+/// How often a logon follows a request from steam to connect to another server
+const MAX_REDIRECTS: u32 = 3;
+
 /// A Connection that hasn't been authentication yet
 pub struct UnAuthenticatedConnection(RawConnection);
 
@@ -27,39 +33,110 @@ impl UnAuthenticatedConnection {
     ///
     /// This allows customizing the transport used by the connection. For example to customize the
     /// TLS configuration, use an existing websocket client or use a proxy.
+    ///
     pub async fn from_sender_receiver<
         Sender: Sink<BytesMut, Error = NetworkError> + Send + 'static,
         Receiver: Stream<Item = Result<BytesMut>> + Send + 'static,
     >(
         sender: Sender,
         receiver: Receiver,
+    ) -> Result<Self, ConnectionError> {
+        Self::from_sender_receiver_with_options(sender, receiver, &ConnectionOptions::default())
+            .await
+    }
+
+    /// Create a connection from a sender, receiver pair with custom connection options.
+    pub async fn from_sender_receiver_with_options<
+        Sender: Sink<BytesMut, Error = NetworkError> + Send + 'static,
+        Receiver: Stream<Item = Result<BytesMut>> + Send + 'static,
+    >(
+        sender: Sender,
+        receiver: Receiver,
+        options: &ConnectionOptions,
     ) -> Result<Self, ConnectionError> {
         Ok(UnAuthenticatedConnection(
-            RawConnection::from_sender_receiver(sender, receiver).await?,
+            RawConnection::from_sender_receiver(sender, receiver, options).await?,
         ))
     }
 
     /// Connect to a server from the server list using the default websocket transport
     pub async fn connect(server_list: &ServerList) -> Result<Self, ConnectionError> {
+        Self::connect_with_config(server_list, &TransportConfig::default()).await
+    }
+
+    /// Connect to a server from the server list using the plain tcp transport
+    pub async fn connect_tcp(server_list: &ServerList) -> Result<Self, ConnectionError> {
+        Self::connect_with_config(
+            server_list,
+            &TransportConfig::default().with_transport(Transport::Tcp),
+        )
+        .await
+    }
+
+    /// Connect to a server from the server list using the configured transport
+    pub async fn connect_with_config(
+        server_list: &ServerList,
+        config: &TransportConfig,
+    ) -> Result<Self, ConnectionError> {
+        Self::connect_with_options(server_list, config, &ConnectionOptions::default()).await
+    }
+
+    /// Connect to a server from the server list using the configured transport and connection options
+    pub async fn connect_with_options(
+        server_list: &ServerList,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
+    ) -> Result<Self, ConnectionError> {
+        Ok(UnAuthenticatedConnection(
+            RawConnection::connect(server_list, config, options).await?,
+        ))
+    }
+
+    /// Connect to a server, keeping the listeners of an existing filter
+    pub(crate) async fn connect_with_filter(
+        server_list: &ServerList,
+        config: &TransportConfig,
+        options: &ConnectionOptions,
+        filter: MessageFilter,
+    ) -> Result<Self, ConnectionError> {
         Ok(UnAuthenticatedConnection(
-            RawConnection::connect(server_list).await?,
+            RawConnection::connect_with_filter(server_list, config, options, filter).await?,
         ))
     }
 
     /// Start an anonymous client session with this connection
     pub async fn anonymous(self) -> Result<Connection, ConnectionError> {
-        let mut raw = self.0;
-        raw.session = anonymous(&raw, AccountType::AnonUser).await?;
-        raw.setup_heartbeat();
-        let connection = Connection::new(raw);
-
-        Ok(connection)
+        self.logon(Credentials::Anonymous(AccountType::AnonUser))
+            .await
     }
 
     /// Start an anonymous server session with this connection
     pub async fn anonymous_server(self) -> Result<Connection, ConnectionError> {
+        self.logon(Credentials::Anonymous(AccountType::AnonGameServer))
+            .await
+    }
+
+    /// Start a session using known credentials
+    ///
+    /// When steam asks us to connect to another server, the logon is retried on a different server from the server list.
+    pub(crate) async fn logon(
+        self,
+        credentials: Credentials,
+    ) -> Result<Connection, ConnectionError> {
         let mut raw = self.0;
-        raw.session = anonymous(&raw, AccountType::AnonGameServer).await?;
+        let mut redirects = 0;
+        raw.session = loop {
+            match logon(&mut raw, &credentials).await {
+                Err(ConnectionError::LoginError(LoginError::TryAnotherCM))
+                    if redirects < MAX_REDIRECTS && raw.server.is_some() =>
+                {
+                    redirects += 1;
+                    raw = raw.redirect().await?;
+                }
+                result => break result?,
+            }
+        };
+        raw.credentials = Some(Arc::new(credentials));
         raw.setup_heartbeat();
         let connection = Connection::new(raw);
 
@@ -114,18 +191,14 @@ impl UnAuthenticatedConnection {
             }
         }
 
-        raw.session = login(
-            &mut raw,
-            account,
-            steam_id,
-            // yes we send the refresh token as access token, yes it makes no sense, yes this is actually required
-            tokens.refresh_token.as_ref(),
-        )
-        .await?;
-        raw.setup_heartbeat();
-        let connection = Connection::new(raw);
-
-        Ok(connection)
+        UnAuthenticatedConnection(raw)
+            .logon(Credentials::Token {
+                account: account.into(),
+                steam_id,
+                // yes we send the refresh token as access token, yes it makes no sense, yes this is actually required
+                token: tokens.refresh_token.as_ref().into(),
+            })
+            .await
     }
 }
 
@@ -133,8 +206,7 @@ impl UnAuthenticatedConnection {
 impl ReadonlyConnection for UnAuthenticatedConnection {
     fn on_notification<T: ServiceMethodRequest>(&self) -> impl Stream<Item = Result<T>> + 'static {
         BroadcastStream::new(self.0.filter.on_notification(T::REQ_NAME))
-            .filter_map(|res| res.ok())
-            .map(|raw| raw.into_notification())
+            .map(|raw| raw.map_err(NetworkError::from)?.into_notification())
     }
 
     fn one_with_header<T: NetMessage + 'static>(
@@ -144,7 +216,7 @@ impl ReadonlyConnection for UnAuthenticatedConnection {
         // to the lifetime of &self
         let fut = self.0.filter.one_kind(T::KIND);
         async move {
-            let raw = fut.await.map_err(|_| NetworkError::EOF)?;
+            let raw = fut.await.map_err(|_| NetworkError::ConnectionClosed)?;
             raw.into_header_and_message()
         }
     }
@@ -154,11 +226,29 @@ impl ReadonlyConnection for UnAuthenticatedConnection {
             .map(|res| res.map(|(_, msg)| msg))
     }
 
+    fn one_matching<T: NetMessage + 'static>(
+        &self,
+        predicate: impl Fn(&NetMessageHeader, &T) -> bool + Send + Sync + 'static,
+    ) -> impl Future<Output = Result<T>> + 'static {
+        let fut = self.0.filter.one_kind_matching(
+            T::KIND,
+            Some(Box::new(move |message: &mut PendingMessage| {
+                message
+                    .decode::<T>()
+                    .is_some_and(|(header, msg)| predicate(header, msg))
+            })),
+        );
+        async move {
+            let raw = fut.await.map_err(|_| NetworkError::ConnectionClosed)?;
+            raw.into_message()
+        }
+    }
+
     fn on_with_header<T: NetMessage + 'static>(
         &self,
     ) -> impl Stream<Item = Result<(NetMessageHeader, T)>> + 'static {
         BroadcastStream::new(self.0.filter.on_kind(T::KIND)).map(|raw| {
-            let raw = raw.map_err(|_| NetworkError::EOF)?;
+            let raw = raw?;
             raw.into_header_and_message()
         })
     }
@@ -185,7 +275,7 @@ pub(crate) async fn service_method_un_authenticated<Msg: ServiceMethodRequest>(
     let message = timeout(connection.timeout, recv)
         .await
         .map_err(|_| NetworkError::Timeout)?
-        .map_err(|_| NetworkError::Timeout)?
+        .map_err(|_| NetworkError::ConnectionClosed)?
         .into_message::<ServiceMethodResponseMessage>()?;
     message.into_response::<Msg>()
 }
@@ -0,0 +1,193 @@
+use super::Result;
+use crate::message::encode_multi;
+use crate::net::{NetMessageHeader, NetworkError, RawNetMessage};
+use futures_util::{Sink, SinkExt};
+use std::pin::pin;
+use std::sync::Arc;
+use tokio::spawn;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, trace};
+
+/// The maximum number of queued messages written to the transport before flushing it
+const MAX_FEED: usize = 32;
+const QUEUE_SIZE: usize = 128;
+
+/// Options for combining outgoing messages into a single "multi" message
+///
+/// Messages are only combined when they are waiting to be written while the previous write is still in progress,
+/// a lone message is never delayed. By default messages are not combined.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchOptions {
+    max_messages: usize,
+    compress_threshold: Option<usize>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions {
+            max_messages: 1,
+            compress_threshold: None,
+        }
+    }
+}
+
+impl BatchOptions {
+    /// Combine up to `max_messages` queued messages into a single multi message
+    pub fn with_max_messages(self, max_messages: usize) -> Self {
+        BatchOptions {
+            max_messages: max_messages.max(1),
+            ..self
+        }
+    }
+
+    /// Gzip compress combined messages that are at least `threshold` bytes
+    pub fn with_compression(self, threshold: usize) -> Self {
+        BatchOptions {
+            compress_threshold: Some(threshold),
+            ..self
+        }
+    }
+
+    fn combine(&self) -> bool {
+        self.max_messages > 1
+    }
+}
+
+pub(crate) enum WriteCommand {
+    Send(Box<RawNetMessage>, oneshot::Sender<Result<()>>),
+    Close(oneshot::Sender<Result<()>>),
+}
+
+/// Start a task that writes the queued messages to the transport
+///
+/// The task stops once the transport is closed or all senders for the queue are dropped.
+pub(crate) fn spawn_writer<S: Sink<RawNetMessage, Error = NetworkError> + Send + 'static>(
+    sink: S,
+    options: BatchOptions,
+) -> mpsc::Sender<WriteCommand> {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    spawn(write(sink, rx, options));
+    tx
+}
+
+async fn write<S: Sink<RawNetMessage, Error = NetworkError>>(
+    sink: S,
+    mut queue: mpsc::Receiver<WriteCommand>,
+    options: BatchOptions,
+) {
+    let mut sink = pin!(sink);
+    let limit = if options.combine() {
+        options.max_messages
+    } else {
+        MAX_FEED
+    };
+    while let Some(command) = queue.recv().await {
+        let mut batch = Vec::new();
+        let mut close = None;
+        match command {
+            WriteCommand::Send(message, reply) => batch.push((message, reply)),
+            WriteCommand::Close(reply) => close = Some(reply),
+        }
+        while close.is_none() && batch.len() < limit {
+            match queue.try_recv() {
+                Ok(WriteCommand::Send(message, reply)) => batch.push((message, reply)),
+                Ok(WriteCommand::Close(reply)) => close = Some(reply),
+                Err(_) => break,
+            }
+        }
+
+        if !batch.is_empty() {
+            let (messages, replies): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let results = if options.combine() && messages.len() > 1 {
+                trace!(count = messages.len(), "combining messages");
+                let header = multi_header(&messages[0]);
+                let messages = messages.into_iter().map(|message| *message);
+                let result = match encode_multi(messages, options.compress_threshold) {
+                    Ok(multi) => match RawNetMessage::from_message(header, multi) {
+                        Ok(multi) => sink.send(multi).await,
+                        Err(e) => Err(e),
+                    },
+                    Err(e) => Err(e.into()),
+                };
+                fan_out(result, replies.len())
+            } else {
+                let mut results = Vec::with_capacity(messages.len());
+                for message in messages {
+                    results.push(sink.feed(*message).await);
+                }
+                if let Err(e) = sink.flush().await {
+                    let mut errors = fan_out(Err(e), results.len()).into_iter();
+                    for result in results.iter_mut().filter(|result| result.is_ok()) {
+                        *result = errors.next().unwrap_or(Err(NetworkError::ConnectionClosed));
+                    }
+                }
+                results
+            };
+            for (reply, result) in replies.into_iter().zip(results) {
+                reply.send(result).ok();
+            }
+        }
+
+        if let Some(reply) = close {
+            debug!("closing transport writer");
+            reply.send(sink.close().await).ok();
+            return;
+        }
+    }
+}
+
+/// The header for a multi message, using the steam id and session of the first message
+fn multi_header(first: &RawNetMessage) -> NetMessageHeader {
+    NetMessageHeader {
+        steam_id: first.header.steam_id,
+        session_id: first.header.session_id,
+        ..NetMessageHeader::default()
+    }
+}
+
+/// Report the result of a single write to everyone waiting for it
+fn fan_out(result: Result<()>, count: usize) -> Vec<Result<()>> {
+    let mut results = Vec::with_capacity(count);
+    match result {
+        Err(e) if count > 1 => {
+            let error = Arc::new(e);
+            while results.len() < count {
+                results.push(Err(NetworkError::WriteFailed(error.clone())));
+            }
+        }
+        result => {
+            results.push(result);
+            while results.len() < count {
+                results.push(Ok(()));
+            }
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_out_shares_error() {
+        let results = fan_out(Err(NetworkError::ConnectionClosed), 3);
+        assert_eq!(results.len(), 3);
+        for result in results {
+            match result {
+                Err(NetworkError::WriteFailed(error)) => {
+                    assert!(matches!(*error, NetworkError::ConnectionClosed))
+                }
+                result => panic!("unexpected result {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn fan_out_single() {
+        let results = fan_out(Err(NetworkError::ConnectionClosed), 1);
+        assert!(matches!(results[..], [Err(NetworkError::ConnectionClosed)]));
+        let results = fan_out(Ok(()), 2);
+        assert!(matches!(results[..], [Ok(()), Ok(())]));
+    }
+}
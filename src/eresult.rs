@@ -1,7 +1,9 @@
 use num_enum::TryFromPrimitive;
 use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
 
-#[derive(TryFromPrimitive, Debug, Copy, Clone)]
+/// Result codes returned by steam
+#[derive(TryFromPrimitive, Debug, Copy, Clone, Eq, PartialEq, Hash)]
 #[repr(i32)]
 #[non_exhaustive]
 pub enum EResult {
@@ -133,9 +135,57 @@ pub enum EResult {
     NoLauncherSpecified = 117,
     MustAgreeToSSA = 118,
     ClientNoLongerSupported = 119,
+    SteamRealmMismatch = 120,
+    InvalidSignature = 121,
+    ParseFailure = 122,
+    NoVerifiedPhone = 123,
+    InsufficientBattery = 124,
+    ChargerRequired = 125,
+    CachedCredentialInvalid = 126,
+    PhoneNumberIsVOIP = 127,
+    NotSupported = 128,
+    FamilySizeLimitExceeded = 129,
+    OfflineAppCacheInvalid = 130,
+}
+
+/// How a failed request should be handled, based on the result returned by steam
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EResultClass {
+    /// The failure is temporary and the request can be retried, preferably after a delay
+    Retryable,
+    /// Retrying the request will fail in the same way
+    Permanent,
+    /// The session is no longer valid, a new logon is required before retrying
+    ReloginRequired,
 }
 
 impl EResult {
+    /// Classify the result to decide how a request that failed with it should be handled
+    pub fn class(self) -> EResultClass {
+        match self {
+            EResult::NoConnection
+            | EResult::Busy
+            | EResult::Timeout
+            | EResult::ServiceUnavailable
+            | EResult::PersistFailed
+            | EResult::LockingFailed
+            | EResult::ConnectFailed
+            | EResult::IOFailure
+            | EResult::RemoteDisconnect
+            | EResult::ServiceReadOnly
+            | EResult::TryAnotherCM
+            | EResult::RemoteCallFailed
+            | EResult::RateLimitExceeded
+            | EResult::TooManyPending => EResultClass::Retryable,
+            EResult::LoggedInElsewhere
+            | EResult::NotLoggedOn
+            | EResult::Revoked
+            | EResult::LogonSessionReplaced => EResultClass::ReloginRequired,
+            _ => EResultClass::Permanent,
+        }
+    }
+
     pub fn from_result(result: i32) -> Result<(), EResult> {
         let result = EResult::try_from(result).unwrap_or(EResult::Invalid);
         match result {
@@ -144,3 +194,168 @@ impl EResult {
         }
     }
 }
+
+impl Display for EResult {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            EResult::Invalid => "invalid result",
+            EResult::OK => "success",
+            EResult::Fail => "generic failure",
+            EResult::NoConnection => "no connection to the steam network",
+            EResult::InvalidPassword => "invalid password or ticket",
+            EResult::LoggedInElsewhere => "the user is logged in elsewhere",
+            EResult::InvalidProtocolVer => "protocol version is incorrect",
+            EResult::InvalidParam => "a parameter is incorrect",
+            EResult::FileNotFound => "file was not found",
+            EResult::Busy => "the called method is busy",
+            EResult::InvalidState => "the called object was in an invalid state",
+            EResult::InvalidName => "the name was invalid",
+            EResult::InvalidEmail => "the email was invalid",
+            EResult::DuplicateName => "the name is not unique",
+            EResult::AccessDenied => "access is denied",
+            EResult::Timeout => "the operation timed out",
+            EResult::Banned => "the user is VAC2 banned",
+            EResult::AccountNotFound => "account not found",
+            EResult::InvalidSteamID => "the steam id was invalid",
+            EResult::ServiceUnavailable => "the requested service is currently unavailable",
+            EResult::NotLoggedOn => "the user is not logged on",
+            EResult::Pending => "the request is pending, it may be in process or waiting on a third party",
+            EResult::EncryptionFailure => "encryption or decryption failed",
+            EResult::InsufficientPrivilege => "insufficient privilege",
+            EResult::LimitExceeded => "too much of a good thing",
+            EResult::Revoked => "access has been revoked",
+            EResult::Expired => "the license or guest pass has expired",
+            EResult::AlreadyRedeemed => "the guest pass has already been redeemed",
+            EResult::DuplicateRequest => "the request is a duplicate and the action has already occurred",
+            EResult::AlreadyOwned => "all the games in the guest pass are already owned",
+            EResult::IPNotFound => "the ip address was not found",
+            EResult::PersistFailed => "failed to write the change to the data store",
+            EResult::LockingFailed => "failed to acquire the access lock for the operation",
+            EResult::LogonSessionReplaced => "the logon session has been replaced",
+            EResult::ConnectFailed => "failed to connect",
+            EResult::HandshakeFailed => "the authentication handshake failed",
+            EResult::IOFailure => "generic io failure",
+            EResult::RemoteDisconnect => "the remote server has disconnected",
+            EResult::ShoppingCartNotFound => "failed to find the shopping cart requested",
+            EResult::Blocked => "a user blocked the action",
+            EResult::Ignored => "the target is ignoring the sender",
+            EResult::NoMatch => "nothing matching the request was found",
+            EResult::AccountDisabled => "the account is disabled",
+            EResult::ServiceReadOnly => "the service is not accepting content changes right now",
+            EResult::AccountNotFeatured => "the account doesn't have value, so this feature isn't available",
+            EResult::AdministratorOK => "allowed to take this action, but only because the requester is an admin",
+            EResult::ContentVersion => "a version mismatch in content transmitted within the steam protocol",
+            EResult::TryAnotherCM => "the current cm can't service the user, try another",
+            EResult::PasswordRequiredToKickSession => "already logged in elsewhere, a password is required to kick the other session",
+            EResult::AlreadyLoggedInElsewhere => "already logged in elsewhere, wait before trying again",
+            EResult::Suspended => "the operation has been suspended",
+            EResult::Cancelled => "the operation has been cancelled",
+            EResult::DataCorruption => "the operation was cancelled because data is ill formed or unrecoverable",
+            EResult::DiskFull => "the operation was cancelled because there is no disk space",
+            EResult::RemoteCallFailed => "the remote or ipc call has failed",
+            EResult::PasswordUnset => "the password could not be verified as it's unset server side",
+            EResult::ExternalAccountUnlinked => "the external account is not linked to a steam account",
+            EResult::PSNTicketInvalid => "the psn ticket was invalid",
+            EResult::ExternalAccountAlreadyLinked => "the external account is already linked to another steam account",
+            EResult::RemoteFileConflict => "the sync cannot resume due to a conflict between the local and remote files",
+            EResult::IllegalPassword => "the requested new password is not allowed",
+            EResult::SameAsPreviousValue => "the new value is the same as the old one",
+            EResult::AccountLogonDenied => "the account logon was denied due to a second factor authentication failure",
+            EResult::CannotUseOldPassword => "the requested new password is the same as the previous password",
+            EResult::InvalidLoginAuthCode => "the account logon was denied due to an invalid auth code",
+            EResult::AccountLogonDeniedNoMail => "the account logon was denied due to a second factor authentication failure, no mail was sent",
+            EResult::HardwareNotCapableOfIPT => "the hardware is not capable of intel identity protection technology",
+            EResult::IPTInitError => "intel identity protection technology failed to initialize",
+            EResult::ParentalControlRestricted => "the operation failed due to parental control restrictions",
+            EResult::FacebookQueryError => "facebook query returned an error",
+            EResult::ExpiredLoginAuthCode => "the account logon was denied due to an expired auth code",
+            EResult::IPLoginRestrictionFailed => "the login failed due to an ip restriction",
+            EResult::AccountLockedDown => "the current user account is currently locked for use",
+            EResult::AccountLogonDeniedVerifiedEmailRequired => "the logon failed because the account's email is not verified",
+            EResult::NoMatchingURL => "there is no url matching the provided values",
+            EResult::BadResponse => "bad response due to a parse failure, missing field, etc",
+            EResult::RequirePasswordReEntry => "the user cannot complete the action until they re-enter their password",
+            EResult::ValueOutOfRange => "the value entered is outside the acceptable range",
+            EResult::UnexpectedError => "something happened that we didn't expect to ever happen",
+            EResult::Disabled => "the requested service has been configured to be unavailable",
+            EResult::InvalidCEGSubmission => "the files submitted to the ceg server are not valid",
+            EResult::RestrictedDevice => "the device being used is not allowed to perform this action",
+            EResult::RegionLocked => "the action could not be complete because it is region restricted",
+            EResult::RateLimitExceeded => "temporary rate limit exceeded, try again later",
+            EResult::AccountLoginDeniedNeedTwoFactor => "two factor authentication is required to log in",
+            EResult::ItemDeleted => "the thing we're trying to access has been deleted",
+            EResult::AccountLoginDeniedThrottle => "login attempt failed, try to throttle response to possible attacker",
+            EResult::TwoFactorCodeMismatch => "the two factor code didn't match",
+            EResult::TwoFactorActivationCodeMismatch => "the two factor activation code didn't match",
+            EResult::AccountAssociatedToMultiplePartners => "the account has been associated with multiple partners",
+            EResult::NotModified => "the data has not been modified",
+            EResult::NoMobileDevice => "the account does not have a mobile device associated with it",
+            EResult::TimeNotSynced => "the time presented is out of range or tolerance",
+            EResult::SMSCodeFailed => "the sms code failed to validate",
+            EResult::AccountLimitExceeded => "too many accounts access this resource",
+            EResult::AccountActivityLimitExceeded => "too many changes to this account",
+            EResult::PhoneActivityLimitExceeded => "too many changes to this phone",
+            EResult::RefundToWallet => "cannot refund to the payment method, must use the wallet",
+            EResult::EmailSendFailure => "cannot send an email",
+            EResult::NotSettled => "can't perform the operation until the payment has settled",
+            EResult::NeedCaptcha => "a captcha is required",
+            EResult::GSLTDenied => "the game server login token owned by the token's owner has been banned",
+            EResult::GSOwnerDenied => "the game server owner has been denied for other reasons",
+            EResult::InvalidItemType => "the type of the item attempted to act on is invalid",
+            EResult::IPBanned => "the ip address has been banned from taking this action",
+            EResult::GSLTExpired => "the game server login token has expired",
+            EResult::InsufficientFunds => "the user does not have enough wallet funds to complete the action",
+            EResult::TooManyPending => "there are too many of this thing pending already",
+            EResult::NoSiteLicensesFound => "no site licenses found",
+            EResult::WGNetworkSendExceeded => "the wg couldn't send a response because we exceeded the max network send size",
+            EResult::AccountNotFriends => "the user is not friends with the account",
+            EResult::LimitedUserAccount => "the user is limited",
+            EResult::CantRemoveItem => "the item can't be removed",
+            EResult::AccountHasBeenDeleted => "the account has been deleted",
+            EResult::AccountHasAnExistingUserCancelledLicense => "a license for this already exists, but was cancelled",
+            EResult::DeniedDueToCommunityCooldown => "access is denied because of a community cooldown",
+            EResult::NoLauncherSpecified => "no launcher was specified",
+            EResult::MustAgreeToSSA => "the user must agree to the steam subscriber agreement",
+            EResult::ClientNoLongerSupported => "the client is no longer supported, the launcher was migrated",
+            EResult::SteamRealmMismatch => "the current steam realm does not match the requested resource",
+            EResult::InvalidSignature => "the signature check failed",
+            EResult::ParseFailure => "failed to parse the input",
+            EResult::NoVerifiedPhone => "the account does not have a verified phone number",
+            EResult::InsufficientBattery => "the device battery is too low to complete the action",
+            EResult::ChargerRequired => "the operation requires a charger to be connected",
+            EResult::CachedCredentialInvalid => "the cached credential was invalid, the user must reauthenticate",
+            EResult::PhoneNumberIsVOIP => "the phone number provided is a voice over ip number",
+            EResult::NotSupported => "the data being accessed is not supported by this api",
+            EResult::FamilySizeLimitExceeded => "reached the maximum size of the family",
+            EResult::OfflineAppCacheInvalid => "the local data for the offline mode cache is insufficient to login",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_results() {
+        assert_eq!(EResult::Busy.class(), EResultClass::Retryable);
+        assert_eq!(EResult::TryAnotherCM.class(), EResultClass::Retryable);
+        assert_eq!(EResult::RateLimitExceeded.class(), EResultClass::Retryable);
+        assert_eq!(EResult::NotLoggedOn.class(), EResultClass::ReloginRequired);
+        assert_eq!(
+            EResult::LogonSessionReplaced.class(),
+            EResultClass::ReloginRequired
+        );
+        assert_eq!(EResult::InvalidPassword.class(), EResultClass::Permanent);
+        assert_eq!(EResult::AccessDenied.class(), EResultClass::Permanent);
+        assert_eq!(EResult::Fail.class(), EResultClass::Permanent);
+    }
+
+    #[test]
+    fn from_result() {
+        assert_eq!(EResult::from_result(1), Ok(()));
+        assert_eq!(EResult::from_result(2), Err(EResult::Fail));
+        assert_eq!(EResult::from_result(-1), Err(EResult::Invalid));
+    }
+}
@@ -1,11 +1,14 @@
 use crate::connection::{ConnectionImpl, ConnectionTrait, MessageFilter, MessageSender};
 use crate::message::EncodableMessage;
 use crate::net::{decode_kind, NetMessageHeader, RawNetMessage};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
 use crate::session::Session;
 use crate::{Connection, NetworkError};
 use futures_util::future::select;
 use protobuf::Message;
 use std::fmt::{Debug, Formatter};
+use std::ops::Deref;
 use std::pin::pin;
 use std::time::Duration;
 use steam_vent_proto::enums_clientserver::EMsg;
@@ -27,6 +30,8 @@ pub struct GameCoordinator {
     sender: MessageSender,
     session: Session,
     timeout: Duration,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
 }
 
 /// While these kinds are consistent between games, they are not defined in the generic steam protobufs.
@@ -99,6 +104,8 @@ impl GameCoordinator {
             sender: connection.sender().clone(),
             session: connection.session().clone().with_app_id(app_id),
             timeout: connection.timeout(),
+            retry_policy: connection.retry_policy(),
+            rate_limiter: connection.rate_limiter(),
         };
 
         connection
@@ -155,11 +162,23 @@ impl ConnectionImpl for GameCoordinator {
         self.timeout
     }
 
+    fn retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+
+    fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    fn connected(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
     fn filter(&self) -> &MessageFilter {
         &self.filter
     }
 
-    fn session(&self) -> &Session {
+    fn session(&self) -> impl Deref<Target = Session> + '_ {
         &self.session
     }
 
@@ -170,17 +189,19 @@ impl ConnectionImpl for GameCoordinator {
         kind: K,
         is_protobuf: bool,
     ) -> Result<(), NetworkError> {
-        let nested_header = NetMessageHeader::default();
-        let mut payload: Vec<u8> = Vec::with_capacity(
-            nested_header.encode_size(kind.into(), is_protobuf) + msg.encode_size(),
-        );
-
-        nested_header.write(&mut payload, kind, is_protobuf)?;
-        msg.write_body(&mut payload)?;
+        let nested = RawNetMessage::from_message_with_kind(
+            NetMessageHeader::default(),
+            msg,
+            kind,
+            is_protobuf,
+        )?;
+        let Some(nested) = self.filter.intercept_outgoing(nested) else {
+            return Ok(());
+        };
         let data = CMsgGCClient {
             appid: Some(self.app_id),
             msgtype: Some(kind.encode_kind(is_protobuf)),
-            payload: Some(payload),
+            payload: Some(nested.into_bytes().to_vec()),
             ..Default::default()
         };
 
@@ -1,20 +1,38 @@
 pub mod auth;
+mod backoff;
+pub mod capture;
 pub mod connection;
 mod eresult;
 mod game_coordinator;
 pub mod message;
 mod net;
+mod rate_limit;
+mod retry;
 mod serverlist;
 mod service_method;
 mod session;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tower")]
+mod tower;
 mod transport;
 
 pub use steam_vent_proto as proto;
 
-pub use connection::{Connection, ConnectionTrait, ReadonlyConnection};
-pub use eresult::EResult;
+pub use backoff::Backoff;
+pub use connection::{
+    BatchOptions, CallOptions, Connection, ConnectionEvent, ConnectionOptions, ConnectionTrait,
+    HeartbeatOptions, Interceptor, ListenerCapacities, OutgoingAction, ReadonlyConnection,
+    ReconnectEvent, ReconnectOptions, ReconnectingConnection, ServiceMethodCall,
+};
+pub use eresult::{EResult, EResultClass};
 pub use game_coordinator::GameCoordinator;
 pub use message::NetMessage;
-pub use net::{NetworkError, RawNetMessage};
-pub use serverlist::{DiscoverOptions, ServerDiscoveryError, ServerList};
+pub use net::{ApiError, NetworkError, ParseLimits, RawNetMessage};
+pub use rate_limit::{RateLimit, RateLimiter};
+pub use retry::RetryPolicy;
+pub use serverlist::{DiscoverOptions, SelectionStrategy, ServerDiscoveryError, ServerList};
 pub use session::{ConnectionError, LoginError};
+#[cfg(feature = "tower")]
+pub use tower::RpcService;
+pub use transport::{Proxy, ProxyError, TlsConfig, Transport, TransportConfig};
@@ -1,10 +1,12 @@
-use crate::net::{NetMessageHeader, NetworkError, RawNetMessage};
+use crate::net::{NetMessageHeader, NetworkError, ParseLimits, RawNetMessage};
 use crate::service_method::ServiceMethodRequest;
 use binread::BinRead;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use crc::{Crc, CRC_32_ISO_HDLC};
 use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use futures_util::{
     future::ready,
     stream::{iter, once},
@@ -63,7 +65,7 @@ impl From<String> for MessageBodyError {
 /// [`raw_send_with_kind`](crate::ConnectionTrait::raw_send_with_kind). To use the higher level messages a struct also needs to implement
 /// [`NetMessage`]
 pub trait EncodableMessage: Sized + Debug + Send {
-    fn read_body(_data: BytesMut, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(_data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         panic!("Reading not implemented for {}", type_name::<Self>())
     }
 
@@ -78,6 +80,34 @@ pub trait EncodableMessage: Sized + Debug + Send {
     fn process_header(&self, _header: &mut NetMessageHeader) {}
 }
 
+/// An already encoded message body, used to send a message multiple times
+#[derive(Debug, Clone)]
+pub(crate) struct EncodedBody(Bytes);
+
+impl EncodedBody {
+    pub fn encode<T: EncodableMessage>(message: &T) -> Result<Self, std::io::Error> {
+        let mut body = BytesMut::with_capacity(message.encode_size());
+        message.write_body((&mut body).writer())?;
+        Ok(EncodedBody(body.freeze()))
+    }
+}
+
+impl From<Bytes> for EncodedBody {
+    fn from(body: Bytes) -> Self {
+        EncodedBody(body)
+    }
+}
+
+impl EncodableMessage for EncodedBody {
+    fn write_body<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        writer.write_all(&self.0)
+    }
+
+    fn encode_size(&self) -> usize {
+        self.0.len()
+    }
+}
+
 /// A message with associated kind
 pub trait NetMessage: EncodableMessage {
     type KindEnum: MsgKindEnum;
@@ -88,17 +118,27 @@ pub trait NetMessage: EncodableMessage {
 #[derive(Debug, BinRead)]
 pub(crate) struct ChannelEncryptRequest {
     pub protocol: u32,
-    #[allow(dead_code)]
     pub universe: u32,
     pub nonce: [u8; 16],
 }
 
 impl EncodableMessage for ChannelEncryptRequest {
-    fn read_body(data: BytesMut, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of {:?} message", Self::KIND);
         let mut reader = Cursor::new(data);
         ChannelEncryptRequest::read(&mut reader).map_err(|e| MalformedBody::new(Self::KIND, e))
     }
+
+    fn write_body<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        trace!("writing body of {:?} message", Self::KIND);
+        writer.write_u32::<LittleEndian>(self.protocol)?;
+        writer.write_u32::<LittleEndian>(self.universe)?;
+        writer.write_all(&self.nonce)
+    }
+
+    fn encode_size(&self) -> usize {
+        4 + 4 + 16
+    }
 }
 
 impl NetMessage for ChannelEncryptRequest {
@@ -112,11 +152,20 @@ pub(crate) struct ChannelEncryptResult {
 }
 
 impl EncodableMessage for ChannelEncryptResult {
-    fn read_body(data: BytesMut, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of {:?} message", Self::KIND);
         let mut reader = Cursor::new(data);
         ChannelEncryptResult::read(&mut reader).map_err(|e| MalformedBody::new(Self::KIND, e))
     }
+
+    fn write_body<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
+        trace!("writing body of {:?} message", Self::KIND);
+        writer.write_u32::<LittleEndian>(self.result)
+    }
+
+    fn encode_size(&self) -> usize {
+        4
+    }
 }
 
 impl NetMessage for ChannelEncryptResult {
@@ -133,6 +182,28 @@ pub(crate) struct ClientEncryptResponse {
 const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
 impl EncodableMessage for ClientEncryptResponse {
+    /// The job ids written in front of the body are read as part of the header
+    fn read_body(data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+        trace!("reading body of {:?} message", Self::KIND);
+        let mut reader = Cursor::new(data);
+        let mut read = || -> std::io::Result<Self> {
+            let protocol = reader.read_u32::<LittleEndian>()?;
+            let key_length = reader.read_u32::<LittleEndian>()? as usize;
+            // don't trust the length with the allocation, the key has to fit in the rest of the body
+            let remaining = reader.get_ref().len() - reader.position() as usize;
+            if key_length > remaining {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            let mut encrypted_key = vec![0; key_length];
+            reader.read_exact(&mut encrypted_key)?;
+            Ok(ClientEncryptResponse {
+                protocol,
+                encrypted_key,
+            })
+        };
+        read().map_err(|e| MalformedBody::new(Self::KIND, e))
+    }
+
     fn write_body<W: Write>(&self, mut writer: W) -> Result<(), std::io::Error> {
         trace!("writing body of {:?} message", Self::KIND);
         writer.write_u64::<LittleEndian>(u64::MAX)?;
@@ -158,79 +229,130 @@ impl NetMessage for ClientEncryptResponse {
     const KIND: Self::KindEnum = EMsg::k_EMsgChannelEncryptResponse;
 }
 
-enum MaybeZipReader {
-    Raw(Cursor<Vec<u8>>),
-    Zipped(Box<GzDecoder<Cursor<Vec<u8>>>>),
-}
-
-impl Read for MaybeZipReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self {
-            MaybeZipReader::Raw(raw) => raw.read(buf),
-            MaybeZipReader::Zipped(zipped) => zipped.read(buf),
-        }
-    }
-}
-
-/// Flatten any "multi" messages in a stream of raw messages
+/// Flatten any "multi" messages in a stream of raw messages, including multi messages nested inside them
 pub(crate) fn flatten_multi<S: Stream<Item = Result<RawNetMessage, NetworkError>>>(
     source: S,
+    limits: ParseLimits,
 ) -> impl Stream<Item = Result<RawNetMessage, NetworkError>> {
-    source.flat_map(|res| match res {
+    source.flat_map(move |res| match res {
         Ok(next) if next.kind == EMsg::k_EMsgMulti => {
-            let reader = Cursor::new(next.data);
-            let multi = match MultiBodyIter::new(reader) {
-                Err(e) => return once(ready(Err(e.into()))).right_stream(),
-                Ok(iter) => iter,
-            };
-            iter(multi).left_stream()
+            iter(MultiBodyIter::new(next.data, limits)).left_stream()
         }
         res => once(ready(res)).right_stream(),
     })
 }
 
-struct MultiBodyIter<R> {
-    reader: R,
-}
-
-impl MultiBodyIter<MaybeZipReader> {
-    pub fn new<R: Read>(mut reader: R) -> Result<Self, MalformedBody> {
-        let mut multi = CMsgMulti::parse_from_reader(&mut reader)
-            .map_err(|e| MalformedBody(EMsg::k_EMsgMulti.into(), e.into()))?;
+/// Combine the encoded messages into the body of a "multi" message, gzip compressing it if it's larger than the threshold
+pub(crate) fn encode_multi(
+    messages: impl IntoIterator<Item = RawNetMessage>,
+    compress_threshold: Option<usize>,
+) -> std::io::Result<CMsgMulti> {
+    let mut body = Vec::new();
+    for message in messages {
+        let bytes = message.into_bytes();
+        body.write_u32::<LittleEndian>(bytes.len() as u32)?;
+        body.extend_from_slice(&bytes);
+    }
 
-        let data = match multi.size_unzipped() {
-            0 => MaybeZipReader::Raw(Cursor::new(multi.take_message_body())),
-            _ => MaybeZipReader::Zipped(Box::new(GzDecoder::new(Cursor::new(
-                multi.take_message_body(),
-            )))),
-        };
+    let mut multi = CMsgMulti::new();
+    if compress_threshold.is_some_and(|threshold| body.len() >= threshold) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body)?;
+        multi.set_size_unzipped(body.len() as u32);
+        multi.set_message_body(encoder.finish()?);
+    } else {
+        multi.set_message_body(body);
+    }
+    trace!(
+        "encoded multi message of {} bytes",
+        multi.message_body().len()
+    );
+    Ok(multi)
+}
 
-        Ok(MultiBodyIter { reader: data })
+/// Iterate over the child messages of a "multi" message
+///
+/// Each body is decompressed once, the child messages are slices of the decompressed body.
+/// Nested multi messages are flattened up to the nesting limit.
+pub(crate) struct MultiBodyIter {
+    /// An encoded multi message that still needs to be decompressed
+    pending: Option<Bytes>,
+    /// The remaining bodies of the multi messages currently being read, innermost last
+    bodies: Vec<Bytes>,
+    limits: ParseLimits,
+}
+
+impl MultiBodyIter {
+    pub fn new(data: Bytes, limits: ParseLimits) -> Self {
+        MultiBodyIter {
+            pending: Some(data),
+            bodies: Vec::new(),
+            limits,
+        }
     }
 }
 
-impl<R: Read> Iterator for MultiBodyIter<R> {
+impl Iterator for MultiBodyIter {
     type Item = Result<RawNetMessage, NetworkError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let size = match self.reader.read_u32::<LittleEndian>() {
-            Ok(size) => size,
-            Err(_) => return None,
-        };
+        loop {
+            if let Some(data) = self.pending.take() {
+                let limit = self.limits.max_nesting();
+                if self.bodies.len() >= limit {
+                    return Some(Err(NetworkError::NestingTooDeep { limit }));
+                }
+                let mut multi = match CMsgMulti::parse_from_bytes(&data) {
+                    Ok(multi) => multi,
+                    Err(e) => return Some(Err(MalformedBody::new(EMsg::k_EMsgMulti, e).into())),
+                };
+                let body = match multi.size_unzipped() {
+                    0 => multi.take_message_body(),
+                    size_unzipped => {
+                        let limit = self.limits.max_decompressed_size();
+                        // don't trust the reported size for the allocation
+                        let mut body = Vec::with_capacity((size_unzipped as usize).min(limit));
+                        let mut decoder =
+                            GzDecoder::new(multi.message_body()).take(limit as u64 + 1);
+                        if let Err(e) = decoder.read_to_end(&mut body) {
+                            return Some(Err(MalformedBody::new(EMsg::k_EMsgMulti, e).into()));
+                        }
+                        if body.len() > limit {
+                            return Some(Err(NetworkError::DecompressedTooLarge { limit }));
+                        }
+                        body
+                    }
+                };
+                self.bodies.push(Bytes::from(body));
+            }
+
+            let body = self.bodies.last_mut()?;
+            if body.remaining() < 4 {
+                self.bodies.pop();
+                continue;
+            }
+            let size = body.get_u32_le() as usize;
+            if body.remaining() < size {
+                self.bodies.pop();
+                return Some(Err(NetworkError::IO(
+                    std::io::ErrorKind::UnexpectedEof.into(),
+                )));
+            }
+
+            let raw = match RawNetMessage::read_with_limits(body.split_to(size), &self.limits) {
+                Ok(raw) => raw,
+                Err(e) => return Some(Err(e)),
+            };
 
-        let mut msg_data = BytesMut::with_capacity(size as usize);
-        msg_data.resize(size as usize, 0);
-        if let Err(e) = self.reader.read_exact(&mut msg_data) {
-            return Some(Err(NetworkError::IO(e)));
-        }
-        let raw = match RawNetMessage::read(msg_data) {
-            Ok(raw) => raw,
-            Err(e) => return Some(Err(e)),
-        };
+            debug!("Reading child message {:?}", raw.kind);
 
-        debug!("Reading child message {:?}", raw.kind);
+            if raw.kind == EMsg::k_EMsgMulti {
+                self.pending = Some(raw.data);
+                continue;
+            }
 
-        Some(Ok(raw))
+            return Some(Ok(raw));
+        }
     }
 }
 
@@ -238,7 +360,7 @@ impl<R: Read> Iterator for MultiBodyIter<R> {
 pub(crate) struct ServiceMethodMessage<Request: Debug>(pub Request);
 
 impl<Request: ServiceMethodRequest + Debug> EncodableMessage for ServiceMethodMessage<Request> {
-    fn read_body(data: BytesMut, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of protobuf message {:?}", Self::KIND);
         Request::parse(&mut data.reader())
             .map_err(|e| MalformedBody::new(Self::KIND, e))
@@ -270,7 +392,7 @@ impl<Request: ServiceMethodRequest + Debug> NetMessage for ServiceMethodMessage<
 #[derive(Debug)]
 pub(crate) struct ServiceMethodResponseMessage {
     job_name: String,
-    body: BytesMut,
+    body: Bytes,
 }
 
 impl ServiceMethodResponseMessage {
@@ -290,7 +412,7 @@ impl ServiceMethodResponseMessage {
 }
 
 impl EncodableMessage for ServiceMethodResponseMessage {
-    fn read_body(data: BytesMut, header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of protobuf message {:?}", Self::KIND);
         Ok(ServiceMethodResponseMessage {
             job_name: header
@@ -312,7 +434,7 @@ impl NetMessage for ServiceMethodResponseMessage {
 #[derive(Debug, Clone)]
 pub(crate) struct ServiceMethodNotification {
     pub(crate) job_name: String,
-    body: BytesMut,
+    body: Bytes,
 }
 
 impl ServiceMethodNotification {
@@ -330,7 +452,7 @@ impl ServiceMethodNotification {
 }
 
 impl EncodableMessage for ServiceMethodNotification {
-    fn read_body(data: BytesMut, header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of protobuf message {:?}", Self::KIND);
         Ok(ServiceMethodNotification {
             job_name: header
@@ -350,7 +472,7 @@ impl NetMessage for ServiceMethodNotification {
 }
 
 impl<ProtoMsg: RpcMessageWithKind + Send> EncodableMessage for ProtoMsg {
-    fn read_body(data: BytesMut, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
+    fn read_body(data: Bytes, _header: &NetMessageHeader) -> Result<Self, MalformedBody> {
         trace!("reading body of protobuf message {:?}", Self::KIND);
         Self::parse(&mut data.reader()).map_err(|e| MalformedBody::new(Self::KIND, e))
     }
@@ -371,3 +493,125 @@ impl<ProtoMsg: RpcMessageWithKind + Send> NetMessage for ProtoMsg {
     const KIND: Self::KindEnum = <ProtoMsg as RpcMessageWithKind>::KIND;
     const IS_PROTOBUF: bool = true;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use steam_vent_proto::steammessages_clientserver_login::CMsgClientHeartBeat;
+
+    fn child() -> RawNetMessage {
+        let heartbeat = CMsgClientHeartBeat {
+            send_reply: Some(true),
+            ..CMsgClientHeartBeat::default()
+        };
+        RawNetMessage::from_message(NetMessageHeader::default(), heartbeat).unwrap()
+    }
+
+    fn multi(messages: Vec<RawNetMessage>, compress: bool) -> RawNetMessage {
+        let multi = encode_multi(messages, compress.then_some(0)).unwrap();
+        RawNetMessage::from_message(NetMessageHeader::default(), multi).unwrap()
+    }
+
+    fn read(
+        message: RawNetMessage,
+        limits: ParseLimits,
+    ) -> Vec<Result<RawNetMessage, NetworkError>> {
+        MultiBodyIter::new(message.data, limits).collect()
+    }
+
+    #[test]
+    fn read_children() {
+        for compress in [false, true] {
+            let children = read(
+                multi(vec![child(), child(), child()], compress),
+                ParseLimits::default(),
+            );
+            assert_eq!(children.len(), 3);
+            for child in children {
+                assert_eq!(child.unwrap().kind, EMsg::k_EMsgClientHeartBeat);
+            }
+        }
+    }
+
+    #[test]
+    fn children_share_decompressed_body() {
+        let children = read(multi(vec![child(), child()], true), ParseLimits::default());
+        let first = children[0].as_ref().unwrap();
+        let second = children[1].as_ref().unwrap();
+        // the children are separated by their 4 byte length prefix
+        let first_end = first.data.as_ptr() as usize + first.data.len();
+        assert_eq!(second.header_buffer.as_ptr() as usize, first_end + 4);
+    }
+
+    #[test]
+    fn flatten_nested() {
+        let nested = multi(vec![child(), multi(vec![child(), child()], true)], false);
+        let children = read(nested, ParseLimits::default());
+        assert_eq!(children.len(), 3);
+        assert!(children.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = multi(vec![child(), multi(vec![child()], false)], false);
+        let children = read(nested, ParseLimits::default().with_max_nesting(1));
+        assert!(children[0].is_ok());
+        assert!(matches!(
+            children[1],
+            Err(NetworkError::NestingTooDeep { limit: 1 })
+        ));
+    }
+
+    #[test]
+    fn decompressed_size_limit() {
+        let message = multi(vec![child(); 100], true);
+        let children = read(
+            message,
+            ParseLimits::default().with_max_decompressed_size(64),
+        );
+        assert!(matches!(
+            children[..],
+            [Err(NetworkError::DecompressedTooLarge { limit: 64 })]
+        ));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(100).unwrap();
+        body.extend_from_slice(&[0; 10]);
+        let mut multi = CMsgMulti::new();
+        multi.set_message_body(body);
+        let message = RawNetMessage::from_message(NetMessageHeader::default(), multi).unwrap();
+
+        let children = read(message, ParseLimits::default());
+        assert!(matches!(children[..], [Err(NetworkError::IO(_))]));
+    }
+
+    fn encrypt_response(key_length: u32, key: &[u8]) -> Bytes {
+        let mut body = Vec::new();
+        body.write_u32::<LittleEndian>(1).unwrap();
+        body.write_u32::<LittleEndian>(key_length).unwrap();
+        body.extend_from_slice(key);
+        body.into()
+    }
+
+    #[test]
+    fn encrypt_response_key() {
+        let response = ClientEncryptResponse::read_body(
+            encrypt_response(3, &[1, 2, 3, 0, 0, 0, 0]),
+            &NetMessageHeader::default(),
+        )
+        .unwrap();
+        assert_eq!(response.encrypted_key, [1, 2, 3]);
+    }
+
+    #[test]
+    fn encrypt_response_key_too_long() {
+        let response = ClientEncryptResponse::read_body(
+            encrypt_response(u32::MAX, &[1, 2, 3]),
+            &NetMessageHeader::default(),
+        );
+        assert!(response.is_err());
+    }
+}
@@ -1,21 +1,98 @@
 use crate::eresult::EResult;
 use crate::message::{EncodableMessage, MalformedBody, NetMessage};
 use crate::proto::steammessages_base::CMsgProtoBufHeader;
+use crate::transport::ProxyError;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use protobuf::Message;
+use protobuf::{Enum, Message};
 use std::borrow::Cow;
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
 use std::io::{Cursor, Seek, SeekFrom};
+use std::sync::Arc;
 use steam_vent_crypto::CryptError;
 use steam_vent_proto::enums_clientserver::EMsg;
 use steam_vent_proto::{MsgKind, MsgKindEnum};
 use steamid_ng::SteamID;
 use thiserror::Error;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
 use tracing::{debug, trace};
 
 pub const PROTO_MASK: u32 = 0x80000000;
 
+/// Limits for parsing incoming messages, protecting against oversized or malicious messages
+///
+/// Messages exceeding a limit are rejected with [`NetworkError::FrameTooLarge`], [`NetworkError::HeaderTooLarge`],
+/// [`NetworkError::DecompressedTooLarge`] or [`NetworkError::NestingTooDeep`].
+#[derive(Debug, Clone, Copy)]
+pub struct ParseLimits {
+    max_frame_size: usize,
+    max_header_size: usize,
+    max_decompressed_size: usize,
+    max_nesting: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_frame_size: 32 * 1024 * 1024,
+            max_header_size: 64 * 1024,
+            max_decompressed_size: 64 * 1024 * 1024,
+            max_nesting: 4,
+        }
+    }
+}
+
+impl ParseLimits {
+    /// Set the maximum size of a single frame received from the transport
+    ///
+    /// Since the transport can't recover from an oversized frame, exceeding this limit closes the connection.
+    pub fn with_max_frame_size(self, max_frame_size: usize) -> Self {
+        ParseLimits {
+            max_frame_size,
+            ..self
+        }
+    }
+
+    /// Set the maximum size of the protobuf header of a message
+    pub fn with_max_header_size(self, max_header_size: usize) -> Self {
+        ParseLimits {
+            max_header_size,
+            ..self
+        }
+    }
+
+    /// Set the maximum size a compressed multi message is allowed to inflate to
+    pub fn with_max_decompressed_size(self, max_decompressed_size: usize) -> Self {
+        ParseLimits {
+            max_decompressed_size,
+            ..self
+        }
+    }
+
+    /// Set how many levels of multi messages can be nested inside each other
+    pub fn with_max_nesting(self, max_nesting: usize) -> Self {
+        ParseLimits {
+            max_nesting: max_nesting.max(1),
+            ..self
+        }
+    }
+
+    pub(crate) fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub(crate) fn max_decompressed_size(&self) -> usize {
+        self.max_decompressed_size
+    }
+
+    pub(crate) fn max_nesting(&self) -> usize {
+        self.max_nesting
+    }
+}
+
+/// Space reserved in front of outgoing messages for the frame header and encryption iv
+pub(crate) const RESERVED_PREFIX: usize = 8 + 16;
+
 #[derive(Debug, Error)]
 #[non_exhaustive]
 pub enum NetworkError {
@@ -23,6 +100,13 @@ pub enum NetworkError {
     IO(#[from] std::io::Error),
     #[error("{0}")]
     Ws(#[from] tokio_tungstenite::tungstenite::Error),
+    /// The server closed the websocket, this ends the stream of a websocket transport
+    ///
+    /// Connections don't treat this as an error, it is reported as [`ConnectionEvent::ServerClosed`](crate::connection::ConnectionEvent::ServerClosed) instead.
+    #[error("Websocket closed by the server with code {code}: {reason}")]
+    WebSocketClosed { code: u16, reason: String },
+    #[error("TLS error: {0}")]
+    Tls(#[from] rustls::Error),
     #[error("Invalid message header")]
     InvalidHeader,
     #[error("Invalid message kind {0}")]
@@ -39,15 +123,128 @@ pub enum NetworkError {
     CryptoError(#[from] CryptError),
     #[error("Unexpected end of stream")]
     EOF,
+    #[error("Connection closed")]
+    ConnectionClosed,
+    /// Writing to the transport failed for a write that contained multiple messages
+    ///
+    /// Every message that was part of the write fails with the same error.
+    #[error("{0}")]
+    WriteFailed(Arc<NetworkError>),
     #[error("Response timed out")]
     Timeout,
-    #[error("Remote returned an error code: {0:?}")]
-    ApiError(EResult),
+    #[error("Listener lagged behind, {0} messages were dropped")]
+    Lagged(u64),
+    #[error("Remote returned an error: {0}")]
+    ApiError(ApiError),
+    #[error("Proxy error: {0}")]
+    Proxy(#[from] ProxyError),
+    #[error("Frame of {size} bytes exceeds the limit of {limit} bytes")]
+    FrameTooLarge { size: usize, limit: usize },
+    #[error("Message header of {size} bytes exceeds the limit of {limit} bytes")]
+    HeaderTooLarge { size: usize, limit: usize },
+    #[error("Decompressed multi message exceeds the limit of {limit} bytes")]
+    DecompressedTooLarge { limit: usize },
+    #[error("Multi messages nested more than {limit} levels deep")]
+    NestingTooDeep { limit: usize },
+}
+
+impl From<BroadcastStreamRecvError> for NetworkError {
+    fn from(value: BroadcastStreamRecvError) -> Self {
+        match value {
+            BroadcastStreamRecvError::Lagged(count) => NetworkError::Lagged(count),
+        }
+    }
 }
 
 impl From<EResult> for NetworkError {
     fn from(value: EResult) -> Self {
-        NetworkError::ApiError(value)
+        NetworkError::ApiError(ApiError::new(value as i32))
+    }
+}
+
+impl NetworkError {
+    /// The result code returned by steam, if this is an [`ApiError`](NetworkError::ApiError)
+    pub fn eresult(&self) -> Option<EResult> {
+        match self {
+            NetworkError::ApiError(error) => Some(error.result()),
+            _ => None,
+        }
+    }
+
+    /// Add the name of the rpc-method that failed to an [`ApiError`](NetworkError::ApiError)
+    pub(crate) fn for_method(self, method: &str) -> Self {
+        match self {
+            NetworkError::ApiError(error) => NetworkError::ApiError(ApiError {
+                method: Some(method.into()),
+                ..error
+            }),
+            error => error,
+        }
+    }
+}
+
+/// An error result returned by steam, with the details of the request that failed
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    result: EResult,
+    code: i32,
+    message: Option<String>,
+    kind: Option<MsgKind>,
+    method: Option<String>,
+}
+
+impl ApiError {
+    fn new(code: i32) -> Self {
+        ApiError {
+            result: EResult::try_from(code).unwrap_or(EResult::Invalid),
+            code,
+            message: None,
+            kind: None,
+            method: None,
+        }
+    }
+
+    /// The result returned by steam, unknown result codes are reported as [`EResult::Invalid`]
+    pub fn result(&self) -> EResult {
+        self.result
+    }
+
+    /// The raw result code returned by steam
+    pub fn code(&self) -> i32 {
+        self.code
+    }
+
+    /// The error message sent by steam, if any
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// The kind of the message that contained the error
+    pub fn kind(&self) -> Option<MsgKind> {
+        self.kind
+    }
+
+    /// The name of the rpc-method that failed
+    pub fn method(&self) -> Option<&str> {
+        self.method.as_deref()
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?}, {})", self.result, self.result, self.code)?;
+        if let Some(method) = &self.method {
+            write!(f, " in {}", method)?;
+        } else if let Some(kind) = self.kind {
+            match EMsg::from_i32(kind.value()) {
+                Some(emsg) => write!(f, " in {:?}", emsg)?,
+                None => write!(f, " in message kind {}", kind.value())?,
+            }
+        }
+        if let Some(message) = &self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
     }
 }
 
@@ -75,6 +272,7 @@ pub struct NetMessageHeader {
     pub session_id: i32,
     pub target_job_name: Option<Cow<'static, str>>,
     pub result: Option<i32>,
+    pub error_message: Option<String>,
     pub source_app_id: Option<u32>,
 }
 
@@ -89,6 +287,7 @@ impl From<CMsgProtoBufHeader> for NetMessageHeader {
                 .has_target_job_name()
                 .then(|| header.target_job_name().to_string().into()),
             result: header.eresult,
+            error_message: header.error_message,
             source_app_id: header.routing_appid,
         }
     }
@@ -99,10 +298,17 @@ impl NetMessageHeader {
         mut reader: R,
         kind: MsgKind,
         is_protobuf: bool,
+        limits: &ParseLimits,
     ) -> Result<(Self, usize)> {
         if is_protobuf {
             let header_length = reader.read_u32::<LittleEndian>()?;
             trace!("reading protobuf header of {} bytes", header_length);
+            if header_length as usize > limits.max_header_size {
+                return Err(NetworkError::HeaderTooLarge {
+                    size: header_length as usize,
+                    limit: limits.max_header_size,
+                });
+            }
             let header = if header_length > 0 {
                 let mut bytes = vec![0; header_length as usize];
                 let num = reader.read(&mut bytes)?;
@@ -114,6 +320,7 @@ impl NetMessageHeader {
             };
             Ok((header, 8 + header_length as usize))
         } else if kind == EMsg::k_EMsgChannelEncryptRequest
+            || kind == EMsg::k_EMsgChannelEncryptResponse
             || kind == EMsg::k_EMsgChannelEncryptResult
         {
             let target_job_id = reader.read_u64::<LittleEndian>()?;
@@ -143,6 +350,7 @@ impl NetMessageHeader {
                     session_id,
                     target_job_name: None,
                     result: None,
+                    error_message: None,
                     source_app_id: None,
                 },
                 4 + 3 + 8 + 8 + 1 + 8 + 4,
@@ -158,6 +366,12 @@ impl NetMessageHeader {
     ) -> std::io::Result<()> {
         if MsgKind::from(kind) == EMsg::k_EMsgChannelEncryptResponse {
             writer.write_u32::<LittleEndian>(kind.value() as u32)?;
+        } else if MsgKind::from(kind) == EMsg::k_EMsgChannelEncryptRequest
+            || MsgKind::from(kind) == EMsg::k_EMsgChannelEncryptResult
+        {
+            writer.write_u32::<LittleEndian>(kind.value() as u32)?;
+            writer.write_u64::<LittleEndian>(self.target_job_id.0)?;
+            writer.write_u64::<LittleEndian>(self.source_job_id.0)?;
         } else if proto {
             trace!("writing header for {:?} protobuf message: {:?}", kind, self);
             let proto_header = self.proto_header(kind.into());
@@ -206,6 +420,10 @@ impl NetMessageHeader {
         if let Some(target_job_name) = self.target_job_name.as_deref() {
             proto_header.set_target_job_name(target_job_name.into());
         }
+        if let Some(result) = self.result {
+            proto_header.set_eresult(result);
+        }
+        proto_header.error_message = self.error_message.clone();
         proto_header.routing_appid = self.source_app_id;
         proto_header
     }
@@ -213,6 +431,10 @@ impl NetMessageHeader {
     pub fn encode_size(&self, kind: MsgKind, proto: bool) -> usize {
         if kind == EMsg::k_EMsgChannelEncryptResponse {
             4
+        } else if kind == EMsg::k_EMsgChannelEncryptRequest
+            || kind == EMsg::k_EMsgChannelEncryptResult
+        {
+            4 + 8 + 8
         } else if proto {
             let proto_header = self.proto_header(kind);
             4 + 4 + proto_header.compute_size() as usize
@@ -227,10 +449,11 @@ pub struct RawNetMessage {
     pub kind: MsgKind,
     pub is_protobuf: bool,
     pub header: NetMessageHeader,
-    pub data: BytesMut,
-    pub(crate) frame_header_buffer: Option<BytesMut>,
-    pub(crate) iv_buffer: Option<BytesMut>,
-    pub(crate) header_buffer: BytesMut,
+    pub data: Bytes,
+    pub(crate) header_buffer: Bytes,
+    /// The allocation `header_buffer` and `data` are slices of, for outgoing messages this has room for the
+    /// frame header and iv in front of the header and the encryption padding after the body
+    pub(crate) buffer: Option<Bytes>,
 }
 
 pub(crate) fn decode_kind(kind: u32) -> (MsgKind, bool) {
@@ -241,7 +464,12 @@ pub(crate) fn decode_kind(kind: u32) -> (MsgKind, bool) {
 
 impl RawNetMessage {
     pub fn read<Body: Into<Bytes>>(body: Body) -> Result<Self> {
-        let mut value = BytesMut::from(body.into());
+        Self::read_with_limits(body, &ParseLimits::default())
+    }
+
+    /// Parse a message, rejecting it if it exceeds the limits
+    pub fn read_with_limits<Body: Into<Bytes>>(body: Body, limits: &ParseLimits) -> Result<Self> {
+        let value = body.into();
         let mut reader = Cursor::new(&value);
         let kind = reader
             .read_u32::<LittleEndian>()
@@ -256,20 +484,18 @@ impl RawNetMessage {
             if is_protobuf { "protobuf " } else { "" }
         );
 
-        let header_start = reader.position() as usize;
-        let (header, body_start) = NetMessageHeader::read(&mut reader, kind, is_protobuf)?;
-
-        value.advance(header_start);
-        let header_buffer = value.split_to(body_start - header_start);
+        let (header, body_start) = NetMessageHeader::read(&mut reader, kind, is_protobuf, limits)?;
+        if body_start > value.len() {
+            return Err(NetworkError::InvalidHeader);
+        }
 
         Ok(RawNetMessage {
             kind,
             is_protobuf,
             header,
-            data: value,
-            frame_header_buffer: None,
-            iv_buffer: None,
-            header_buffer,
+            header_buffer: value.slice(..body_start),
+            data: value.slice(body_start..),
+            buffer: Some(value),
         })
     }
 
@@ -295,45 +521,100 @@ impl RawNetMessage {
         //
         // 8 byte frame header, 16 byte iv, header, body, 16 byte encryption padding
         let mut buff = BytesMut::with_capacity(
-            8 + 16 + header.encode_size(kind.into(), is_protobuf) + body_size + 16,
+            RESERVED_PREFIX + header.encode_size(kind.into(), is_protobuf) + body_size + 16,
         );
-        buff.extend([0; 8 + 16]);
-        let frame_header_buffer = buff.split_to(8);
-        let iv_buffer = buff.split_to(16);
+        buff.extend([0; RESERVED_PREFIX]);
 
-        {
-            let mut writer = (&mut buff).writer();
-            header.write(&mut writer, kind, is_protobuf)?;
-        }
+        let mut writer = (&mut buff).writer();
+        header.write(&mut writer, kind, is_protobuf)?;
+        let body_start = buff.len();
 
-        let header_buffer = buff.split();
         let mut writer = (&mut buff).writer();
         message.write_body(&mut writer)?;
-        trace!("encoded body({} bytes): {:x?}", buff.len(), buff.as_ref());
+        trace!(
+            "encoded body({} bytes): {:x?}",
+            buff.len() - body_start,
+            &buff[body_start..]
+        );
 
+        let buffer = buff.freeze();
         Ok(RawNetMessage {
             kind: kind.into(),
             is_protobuf,
             header,
-            data: buff,
-            frame_header_buffer: Some(frame_header_buffer),
-            iv_buffer: Some(iv_buffer),
-            header_buffer,
+            header_buffer: buffer.slice(RESERVED_PREFIX..body_start),
+            data: buffer.slice(body_start..),
+            buffer: Some(buffer),
         })
     }
 
     /// Return a buffer containing the raw message bytes
     pub fn into_bytes(self) -> BytesMut {
-        let mut body = self.header_buffer;
-        body.unsplit(self.data);
-        body
+        self.into_writable(0)
+    }
+
+    /// Join the header and body into a writable buffer, with `prefix` bytes of space in front of the header
+    ///
+    /// When the header and body are still adjacent in an allocation that isn't shared with any other message
+    /// and has enough space in front, that allocation is reused instead of copying the message.
+    pub(crate) fn into_writable(self, prefix: usize) -> BytesMut {
+        let RawNetMessage {
+            header_buffer,
+            data,
+            buffer,
+            ..
+        } = self;
+        let len = header_buffer.len() + data.len();
+
+        if let Some(buffer) = buffer {
+            let base = buffer.as_ptr() as usize;
+            let start = header_buffer.as_ptr() as usize;
+            let adjacent = data.is_empty() || start + header_buffer.len() == data.as_ptr() as usize;
+            let contained = start >= base + prefix && start + len <= base + buffer.len();
+            if adjacent && contained {
+                let offset = start - base - prefix;
+                drop(header_buffer);
+                drop(data);
+                match buffer.try_into_mut() {
+                    Ok(mut buffer) => {
+                        buffer.advance(offset);
+                        buffer.truncate(prefix + len);
+                        return buffer;
+                    }
+                    Err(buffer) => {
+                        let start = offset + prefix;
+                        return copy_with_prefix(prefix, &[&buffer[start..start + len]]);
+                    }
+                }
+            }
+        }
+
+        copy_with_prefix(prefix, &[&header_buffer, &data])
+    }
+}
+
+fn copy_with_prefix(prefix: usize, parts: &[&[u8]]) -> BytesMut {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    // leave room for the encryption padding
+    let mut buff = BytesMut::with_capacity(prefix + len + 16);
+    buff.resize(prefix, 0);
+    for part in parts {
+        buff.extend_from_slice(part);
     }
+    buff
 }
 
 impl RawNetMessage {
     pub fn into_header_and_message<T: NetMessage>(self) -> Result<(NetMessageHeader, T)> {
         if let Some(result) = self.header.result {
-            EResult::from_result(result)?;
+            if EResult::from_result(result).is_err() {
+                return Err(NetworkError::ApiError(ApiError {
+                    message: self.header.error_message.filter(|msg| !msg.is_empty()),
+                    kind: Some(self.kind),
+                    method: self.header.target_job_name.map(String::from),
+                    ..ApiError::new(result)
+                }));
+            }
         }
         if self.kind == T::KIND {
             trace!(
@@ -352,3 +633,75 @@ impl RawNetMessage {
         self.into_header_and_message().map(|(_, msg)| msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use steam_vent_proto::steammessages_clientserver_login::CMsgClientHeartBeat;
+
+    fn message() -> RawNetMessage {
+        let heartbeat = CMsgClientHeartBeat {
+            send_reply: Some(true),
+            ..CMsgClientHeartBeat::default()
+        };
+        RawNetMessage::from_message(NetMessageHeader::default(), heartbeat).unwrap()
+    }
+
+    fn contents(message: &RawNetMessage) -> Vec<u8> {
+        [&message.header_buffer[..], &message.data[..]].concat()
+    }
+
+    #[test]
+    fn writable_reuses_allocation() {
+        let message = message();
+        let expected = contents(&message);
+        let base = message.buffer.as_ref().unwrap().as_ptr();
+
+        let writable = message.into_writable(RESERVED_PREFIX);
+        assert_eq!(writable.as_ptr(), base);
+        assert_eq!(&writable[RESERVED_PREFIX..], &expected[..]);
+    }
+
+    #[test]
+    fn writable_with_smaller_prefix() {
+        let message = message();
+        let expected = contents(&message);
+        let base = message.buffer.as_ref().unwrap().as_ptr();
+
+        let writable = message.into_bytes();
+        assert_eq!(writable.as_ptr(), base.wrapping_add(RESERVED_PREFIX));
+        assert_eq!(&writable[..], &expected[..]);
+    }
+
+    #[test]
+    fn writable_copies_shared_allocation() {
+        let message = message();
+        let expected = contents(&message);
+        let shared = message.clone();
+
+        let writable = message.into_writable(RESERVED_PREFIX);
+        assert_eq!(writable.len(), RESERVED_PREFIX + expected.len());
+        assert_eq!(&writable[RESERVED_PREFIX..], &expected[..]);
+        assert_eq!(contents(&shared), expected);
+    }
+
+    #[test]
+    fn writable_copies_without_room_for_prefix() {
+        let bytes = message().into_bytes().freeze();
+        let message = RawNetMessage::read(bytes.clone()).unwrap();
+
+        let writable = message.into_writable(RESERVED_PREFIX);
+        assert_eq!(&writable[..RESERVED_PREFIX], &[0; RESERVED_PREFIX]);
+        assert_eq!(&writable[RESERVED_PREFIX..], &bytes[..]);
+    }
+
+    #[test]
+    fn writable_copies_separate_parts() {
+        let mut message = message();
+        message.data = Bytes::from_static(b"body");
+        let expected = contents(&message);
+
+        let writable = message.into_writable(RESERVED_PREFIX);
+        assert_eq!(&writable[RESERVED_PREFIX..], &expected[..]);
+    }
+}
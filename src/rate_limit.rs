@@ -0,0 +1,292 @@
+use crate::eresult::EResult;
+use crate::net::NetworkError;
+use crate::service_method::ServiceMethodRequest;
+use dashmap::DashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use steam_vent_proto::MsgKindEnum;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, warn};
+
+/// The allowed rate for a single rpc-method or message kind
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    requests: u32,
+    per: Duration,
+    burst: u32,
+    min_fraction: f64,
+}
+
+impl RateLimit {
+    /// Allow `requests` requests in every `per` interval
+    ///
+    /// By default up to `requests` requests can be sent in a burst.
+    pub fn new(requests: u32, per: Duration) -> Self {
+        let requests = requests.max(1);
+        RateLimit {
+            requests,
+            per,
+            burst: requests,
+            min_fraction: 0.1,
+        }
+    }
+
+    /// Allow `requests` requests every second
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    /// Allow `requests` requests every minute
+    pub fn per_minute(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60))
+    }
+
+    /// Set the number of requests that can be sent at once after being idle
+    pub fn with_burst(self, burst: u32) -> Self {
+        RateLimit {
+            burst: burst.max(1),
+            ..self
+        }
+    }
+
+    /// Set the lowest fraction of the configured rate the limiter will slow down to when steam reports that
+    /// the rate limit was exceeded, defaults to `0.1`
+    pub fn with_min_fraction(self, min_fraction: f64) -> Self {
+        RateLimit {
+            min_fraction: min_fraction.clamp(0.01, 1.0),
+            ..self
+        }
+    }
+
+    /// The configured rate in requests per second
+    fn rate(&self) -> f64 {
+        self.requests as f64 / self.per.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// What a request is rate limited by
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum RateLimitKey {
+    Method(&'static str),
+    Kind(&'static str, i32),
+}
+
+impl RateLimitKey {
+    pub fn kind<K: MsgKindEnum>(kind: K) -> Self {
+        RateLimitKey::Kind(K::NAME, kind.enum_value())
+    }
+}
+
+/// Token-bucket rate limiter for the requests send to steam
+///
+/// Limits can be configured per rpc-method and per message kind, requests without a configured limit are sent
+/// without delay. Requests exceeding the limit are queued until they can be sent.
+///
+/// When steam responds with [`EResult::RateLimitExceeded`] the rate of the method is halved, after which it slowly
+/// recovers to the configured rate with every successful request.
+///
+/// The limiter can be cloned to share the limits between multiple connections for the same account.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    methods: Arc<DashMap<String, Arc<Bucket>>>,
+    kinds: Arc<DashMap<(&'static str, i32), Arc<Bucket>>>,
+}
+
+impl Debug for RateLimiter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("methods", &self.methods.len())
+            .field("kinds", &self.kinds.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the rate of a rpc-method
+    pub fn with_method<Msg: ServiceMethodRequest>(self, limit: RateLimit) -> Self {
+        self.with_method_name(Msg::REQ_NAME, limit)
+    }
+
+    /// Limit the rate of a rpc-method by name, like `"Player.GetOwnedGames#1"`
+    pub fn with_method_name(self, name: impl Into<String>, limit: RateLimit) -> Self {
+        self.methods
+            .insert(name.into(), Arc::new(Bucket::new(limit)));
+        self
+    }
+
+    /// Limit the rate of messages or jobs of a specific kind
+    pub fn with_kind<K: MsgKindEnum>(self, kind: K, limit: RateLimit) -> Self {
+        self.kinds
+            .insert((K::NAME, kind.enum_value()), Arc::new(Bucket::new(limit)));
+        self
+    }
+
+    fn bucket(&self, key: RateLimitKey) -> Option<Arc<Bucket>> {
+        match key {
+            RateLimitKey::Method(name) => self.methods.get(name).map(|bucket| bucket.clone()),
+            RateLimitKey::Kind(name, value) => {
+                self.kinds.get(&(name, value)).map(|bucket| bucket.clone())
+            }
+        }
+    }
+
+    /// Wait until a request can be sent
+    ///
+    /// Waiting requests are let through in the order they started waiting.
+    pub(crate) async fn acquire(&self, key: RateLimitKey) {
+        if let Some(bucket) = self.bucket(key) {
+            bucket.acquire(key).await;
+        }
+    }
+
+    /// Adjust the rate based on the outcome of a request
+    pub(crate) fn record<T>(&self, key: RateLimitKey, result: &Result<T, NetworkError>) {
+        let Some(bucket) = self.bucket(key) else {
+            return;
+        };
+        match result {
+            Ok(_) => bucket.recover(),
+            Err(error) if error.eresult() == Some(EResult::RateLimitExceeded) => {
+                bucket.slow_down(key)
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+struct Bucket {
+    limit: RateLimit,
+    // the current rate in requests per second, lowered when steam reports that we exceed the limit
+    rate: std::sync::Mutex<f64>,
+    // an async mutex is held while waiting for a token, so waiters are served in order
+    // and a cancelled waiter doesn't use up a token
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: RateLimit) -> Self {
+        Bucket {
+            rate: std::sync::Mutex::new(limit.rate()),
+            state: Mutex::new(BucketState {
+                tokens: limit.burst as f64,
+                updated: Instant::now(),
+            }),
+            limit,
+        }
+    }
+
+    fn rate(&self) -> f64 {
+        *self.rate.lock().unwrap()
+    }
+
+    async fn acquire(&self, key: RateLimitKey) {
+        let mut state = self.state.lock().await;
+        loop {
+            let rate = self.rate();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.updated).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rate).min(self.limit.burst as f64);
+            state.updated = now;
+            if state.tokens >= 1.0 {
+                state.tokens -= 1.0;
+                return;
+            }
+            let delay = Duration::from_secs_f64((1.0 - state.tokens) / rate);
+            debug!(?key, ?delay, "rate limit reached, delaying request");
+            sleep(delay).await;
+        }
+    }
+
+    fn slow_down(&self, key: RateLimitKey) {
+        let mut rate = self.rate.lock().unwrap();
+        let min = self.limit.rate() * self.limit.min_fraction;
+        *rate = (*rate / 2.0).max(min);
+        warn!(
+            ?key,
+            rate = *rate,
+            "steam reported rate limit exceeded, lowering rate"
+        );
+    }
+
+    fn recover(&self) {
+        let mut rate = self.rate.lock().unwrap();
+        let max = self.limit.rate();
+        *rate = (*rate + max / 20.0).min(max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use steam_vent_proto::enums_clientserver::EMsg;
+
+    const KEY: RateLimitKey = RateLimitKey::Method("Player.GetOwnedGames#1");
+
+    fn limiter(limit: RateLimit) -> RateLimiter {
+        RateLimiter::new().with_method_name("Player.GetOwnedGames#1", limit)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_then_rate() {
+        let limiter = limiter(RateLimit::per_second(2).with_burst(3));
+        let start = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(KEY).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        limiter.acquire(KEY).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        limiter.acquire(KEY).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unlimited_requests_are_not_delayed() {
+        let limiter = limiter(RateLimit::per_minute(1))
+            .with_kind(EMsg::k_EMsgClientGamesPlayed, RateLimit::per_minute(1));
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter
+                .acquire(RateLimitKey::Method("Player.GetGameBadgeLevels#1"))
+                .await;
+            limiter
+                .acquire(RateLimitKey::kind(EMsg::k_EMsgClientLogon))
+                .await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[test]
+    fn slow_down_and_recover() {
+        let limiter = limiter(RateLimit::per_second(10).with_min_fraction(0.25));
+        let bucket = limiter.bucket(KEY).unwrap();
+        let rate_limited: Result<(), NetworkError> = Err(EResult::RateLimitExceeded.into());
+
+        limiter.record(KEY, &rate_limited);
+        assert_eq!(bucket.rate(), 5.0);
+        limiter.record(KEY, &rate_limited);
+        limiter.record(KEY, &rate_limited);
+        assert_eq!(bucket.rate(), 2.5);
+
+        // other errors don't change the rate
+        limiter.record(KEY, &Err::<(), _>(EResult::Fail.into()));
+        assert_eq!(bucket.rate(), 2.5);
+
+        for _ in 0..20 {
+            limiter.record(KEY, &Ok(()));
+        }
+        assert_eq!(bucket.rate(), 10.0);
+    }
+}
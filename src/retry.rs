@@ -0,0 +1,73 @@
+use crate::backoff::Backoff;
+use crate::eresult::EResultClass;
+use crate::net::NetworkError;
+use std::time::Duration;
+
+/// Policy for retrying failed rpc-requests and jobs
+///
+/// Requests that fail with a [retryable](EResultClass::Retryable) result are retried.
+/// Requests that time out or fail because of the connection are only retried when the request is marked as idempotent,
+/// since steam might already have processed the request.
+///
+/// The default policy doesn't retry any requests.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    backoff: Backoff,
+    max_attempts: u32,
+    idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            backoff: Backoff::default()
+                .with_initial(Duration::from_millis(500))
+                .with_max(Duration::from_secs(10))
+                .with_jitter(0.5),
+            max_attempts: 1,
+            idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set the backoff policy used between attempts
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        RetryPolicy { backoff, ..self }
+    }
+
+    /// Set the maximum number of attempts, including the first one
+    pub fn with_max_attempts(self, max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts,
+            ..self
+        }
+    }
+
+    /// Mark the requests as safe to send multiple times
+    pub fn with_idempotent(self, idempotent: bool) -> Self {
+        RetryPolicy { idempotent, ..self }
+    }
+
+    /// Check if a request that failed with `error` in the given (zero based) attempt should be retried
+    pub fn should_retry(&self, error: &NetworkError, attempt: u32) -> bool {
+        if attempt.saturating_add(1) >= self.max_attempts {
+            return false;
+        }
+        match error {
+            NetworkError::ApiError(error) => error.result().class() == EResultClass::Retryable,
+            NetworkError::WriteFailed(error) => self.should_retry(error, attempt),
+            NetworkError::Timeout
+            | NetworkError::IO(_)
+            | NetworkError::Ws(_)
+            | NetworkError::EOF
+            | NetworkError::ConnectionClosed => self.idempotent,
+            _ => false,
+        }
+    }
+
+    /// Get the delay before retrying after the given (zero based) attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.delay(attempt)
+    }
+}
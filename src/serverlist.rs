@@ -1,12 +1,23 @@
+use crate::transport::Proxy;
+use futures_util::future::{join, join_all};
 use rand::prelude::*;
 use reqwest::{Client, Error};
-use serde::Deserialize;
-use std::iter::Cycle;
-use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::vec::IntoIter;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use steam_vent_proto::steammessages_clientserver::CMsgClientCMList;
 use thiserror::Error;
-use tracing::debug;
+use tokio::fs;
+use tokio::net::TcpStream;
+use tokio::spawn;
+use tokio::time::timeout;
+use tracing::{debug, warn};
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -25,12 +36,33 @@ impl From<reqwest::Error> for ServerDiscoveryError {
     }
 }
 
+/// How servers are picked from the server list
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SelectionStrategy {
+    /// Pick servers in a random order, rotating between them for reconnects
+    #[default]
+    Random,
+    /// Measure the connect time to a number of random candidates during discovery and prefer the fastest ones
+    ///
+    /// When a proxy is configured the probes are made through the proxy, measuring the time to open a tunnel
+    /// to the server.
+    Latency { candidates: usize },
+    /// Prefer the servers with the lowest weighted load as reported by steam for the configured cell
+    Load,
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct DiscoverOptions {
     web_client: Option<Client>,
+    proxy: Option<Proxy>,
     // todo: some smart cell based routing based on
     // https://raw.githubusercontent.com/SteamDatabase/SteamTracking/6d23ebb0070998ae851278cfae5f38832f4ac28d/ClientExtracted/steam/cached/CellMap.vdf
     cell: u8,
+    strategy: SelectionStrategy,
+    cool_down: Option<Duration>,
+    cache_path: Option<PathBuf>,
+    cache_ttl: Option<Duration>,
 }
 
 impl DiscoverOptions {
@@ -44,12 +76,60 @@ impl DiscoverOptions {
     pub fn with_cell(self, cell: u8) -> Self {
         DiscoverOptions { cell, ..self }
     }
+
+    /// Send the discovery request through a proxy
+    ///
+    /// This is ignored when a custom web client is set. Using a socks5 proxy requires the `socks` feature.
+    pub fn with_proxy(self, proxy: Proxy) -> Self {
+        DiscoverOptions {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Set the strategy used to pick servers from the list
+    pub fn with_strategy(self, strategy: SelectionStrategy) -> Self {
+        DiscoverOptions { strategy, ..self }
+    }
+
+    /// Cache the discovered server list in a file
+    pub fn with_cache_path(self, cache_path: impl Into<PathBuf>) -> Self {
+        DiscoverOptions {
+            cache_path: Some(cache_path.into()),
+            ..self
+        }
+    }
+
+    /// Set how long a cached server list is used without waiting for discovery, defaults to 1 day
+    pub fn with_cache_ttl(self, cache_ttl: Duration) -> Self {
+        DiscoverOptions {
+            cache_ttl: Some(cache_ttl),
+            ..self
+        }
+    }
+
+    /// Set how long a server that failed to connect is skipped for, defaults to 5 minutes
+    pub fn with_cool_down(self, cool_down: Duration) -> Self {
+        DiscoverOptions {
+            cool_down: Some(cool_down),
+            ..self
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(5 * 60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A list of steam servers to connect to
+///
+/// The list can be serialized to store it, deserializing restores it in the same way as [`from_addresses`](Self::from_addresses).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "ServerAddresses", try_from = "ServerAddresses")]
 pub struct ServerList {
-    servers: Arc<Mutex<Cycle<IntoIter<SocketAddr>>>>,
-    ws_servers: Arc<Mutex<Cycle<IntoIter<String>>>>,
+    servers: Arc<Mutex<ServerPool<SocketAddr>>>,
+    ws_servers: Arc<Mutex<ServerPool<String>>>,
+    cool_down: Duration,
 }
 
 impl ServerList {
@@ -57,68 +137,479 @@ impl ServerList {
         Self::discover_with(DiscoverOptions::default()).await
     }
 
+    /// Discover the available servers
+    ///
+    /// When a cache path is configured, a cached list that is younger than the cache ttl is used directly
+    /// and refreshed in the background. Older lists are only used when the discovery request fails.
     pub async fn discover_with(
         options: DiscoverOptions,
     ) -> Result<ServerList, ServerDiscoveryError> {
-        let client = options.web_client.unwrap_or_default();
-        let cell = options.cell;
-
-        let response: ServerListResponse = client
-            .get(format!(
-                "https://api.steampowered.com/ISteamDirectory/GetCMList/v1/?cellid={cell}"
-            ))
-            .send()
-            .await?
-            .json()
-            .await?;
-        if response.response.server_list.is_empty() {
+        let Some(cache_path) = options.cache_path.clone() else {
+            return ServerList::new(fetch(&options).await?, &options);
+        };
+
+        let cached = read_cache(&cache_path).await;
+        let ttl = options.cache_ttl.unwrap_or(DEFAULT_CACHE_TTL);
+        match cached {
+            Some((addresses, age)) if age < ttl => {
+                debug!(?age, "using cached server list");
+                let list = ServerList::new(addresses, &options)?;
+                spawn(list.clone().refresh(options));
+                Ok(list)
+            }
+            cached => match fetch(&options).await {
+                Ok(addresses) => {
+                    write_cache(&cache_path, &addresses).await;
+                    ServerList::new(addresses, &options)
+                }
+                Err(e) => match cached {
+                    Some((addresses, age)) => {
+                        warn!(error = ?e, ?age, "failed to discover servers, using stale cache");
+                        ServerList::new(addresses, &options)
+                    }
+                    None => Err(e),
+                },
+            },
+        }
+    }
+
+    /// Create a server list from a static list of addresses
+    ///
+    /// The websocket servers are specified as `host:port`, or as a full url like `ws://host:port/cmsocket/`.
+    pub fn from_addresses(
+        servers: impl IntoIterator<Item = SocketAddr>,
+        ws_servers: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Result<ServerList, ServerDiscoveryError> {
+        ServerList::new(
+            ServerAddresses {
+                servers: servers.into_iter().collect(),
+                ws_servers: ws_servers.into_iter().map(Into::into).collect(),
+            },
+            &DiscoverOptions::default(),
+        )
+    }
+
+    fn new(
+        addresses: ServerAddresses,
+        options: &DiscoverOptions,
+    ) -> Result<ServerList, ServerDiscoveryError> {
+        let ServerAddresses {
+            servers,
+            ws_servers,
+        } = addresses;
+        if servers.is_empty() {
             return Err(ServerDiscoveryError::NoServers);
         }
-        if response.response.server_list.is_empty() {
+        if ws_servers.is_empty() {
             return Err(ServerDiscoveryError::NoWsServers);
         }
-        Ok(response.into())
+        let rotate = options.strategy == SelectionStrategy::Random;
+        Ok(ServerList {
+            servers: Arc::new(Mutex::new(ServerPool::new(servers, rotate))),
+            ws_servers: Arc::new(Mutex::new(ServerPool::new(ws_servers, rotate))),
+            cool_down: options.cool_down.unwrap_or(DEFAULT_COOL_DOWN),
+        })
     }
 
-    /// Pick a server from the server list, rotating them in a round-robin way for reconnects.
+    /// Update the list and cache with freshly discovered servers
+    async fn refresh(self, options: DiscoverOptions) {
+        match fetch(&options).await {
+            Ok(addresses) => {
+                debug!("refreshed server list");
+                if let Some(cache_path) = &options.cache_path {
+                    write_cache(cache_path, &addresses).await;
+                }
+                self.servers.lock().unwrap().replace(addresses.servers);
+                self.ws_servers
+                    .lock()
+                    .unwrap()
+                    .replace(addresses.ws_servers);
+            }
+            Err(e) => warn!(error = ?e, "failed to refresh server list"),
+        }
+    }
+
+    /// Pick a server from the server list.
+    ///
+    /// With the random strategy, servers are rotated in a round-robin way for reconnects,
+    /// otherwise the most preferred server is picked. Servers that are cooling down after failing to connect are skipped.
     ///
     /// # Returns
     /// The selected `SocketAddr`
     pub fn pick(&self) -> SocketAddr {
-        // SAFETY:
-        // `lock` cannot panic as we cannot lock again within the same thread.
-        // `unwrap` is safe as `discover_with` already checks for servers being present.
-        let addr = self.servers.lock().unwrap().next().unwrap();
-        debug!(addr = ?addr, "picked server from list");
-        addr
+        self.pick_many(1)[0]
     }
 
-    /// Pick a WebSocket server from the server list, rotating them in a round-robin way for reconnects.
+    /// Pick a WebSocket server from the server list, in the same way as [`pick`](Self::pick).
     ///
     /// # Returns
     /// A WebSocket URL to connect to, if the server list contains any servers.
     pub fn pick_ws(&self) -> String {
-        // SAFETY: Same as for `pick`.
-        let addr = self.ws_servers.lock().unwrap().next().unwrap();
-        debug!(addr = ?addr, "picked websocket server from list");
-        format!("wss://{addr}/cmsocket/")
+        ws_url(&self.pick_many_ws(1)[0])
+    }
+
+    /// Pick up to `count` different servers to connect to in parallel
+    pub(crate) fn pick_many(&self, count: usize) -> Vec<SocketAddr> {
+        // SAFETY:
+        // `lock` cannot panic as we cannot lock again within the same thread.
+        let addrs = self.servers.lock().unwrap().pick_many(count);
+        debug!(addrs = ?addrs, "picked servers from list");
+        addrs
+    }
+
+    /// Pick up to `count` different websocket servers to connect to in parallel, as `host:port`
+    pub(crate) fn pick_many_ws(&self, count: usize) -> Vec<String> {
+        // SAFETY: Same as for `pick_many`.
+        let addrs = self.ws_servers.lock().unwrap().pick_many(count);
+        debug!(addrs = ?addrs, "picked websocket servers from list");
+        addrs
+    }
+
+    /// Skip a server that failed to connect for a while
+    pub(crate) fn cool_down(&self, addr: SocketAddr) {
+        debug!(addr = ?addr, "server failed to connect, cooling down");
+        self.servers.lock().unwrap().cool_down(addr, self.cool_down);
+    }
+
+    /// Skip a websocket server that failed to connect for a while
+    pub(crate) fn cool_down_ws(&self, addr: &str) {
+        debug!(addr = ?addr, "websocket server failed to connect, cooling down");
+        self.ws_servers
+            .lock()
+            .unwrap()
+            .cool_down(addr.into(), self.cool_down);
+    }
+
+    /// Skip the server of an existing connection for a while, when steam asks us to connect to another server
+    pub(crate) fn cool_down_server(&self, server: &CmServer) {
+        match server {
+            CmServer::Tcp(addr) => self.cool_down(*addr),
+            CmServer::WebSocket(addr) => self.cool_down_ws(addr),
+        }
     }
-}
 
-impl From<ServerListResponse> for ServerList {
-    fn from(value: ServerListResponse) -> Self {
-        let (mut servers, mut ws_servers) = (
-            value.response.server_list,
-            value.response.server_list_websockets,
+    /// Merge a list of servers pushed by steam, the new servers are preferred for the next picks
+    pub(crate) fn merge_cm_list(&self, list: &CMsgClientCMList) {
+        let servers: Vec<SocketAddr> = list
+            .cm_addresses
+            .iter()
+            .zip(list.cm_ports.iter())
+            .filter_map(|(ip, port)| {
+                Some(SocketAddr::new(
+                    Ipv4Addr::from(*ip).into(),
+                    u16::try_from(*port).ok()?,
+                ))
+            })
+            .collect();
+        let ws_servers = list.cm_websocket_addresses.clone();
+        debug!(
+            servers = servers.len(),
+            ws_servers = ws_servers.len(),
+            "received server list from steam"
         );
+        self.servers.lock().unwrap().merge(servers);
+        self.ws_servers.lock().unwrap().merge(ws_servers);
+    }
+}
+
+/// The server a connection was made to
+#[derive(Debug, Clone)]
+pub(crate) enum CmServer {
+    Tcp(SocketAddr),
+    WebSocket(String),
+}
+
+/// Request the server list from the steam directory
+async fn fetch(options: &DiscoverOptions) -> Result<ServerAddresses, ServerDiscoveryError> {
+    let client = match (&options.web_client, &options.proxy) {
+        (Some(client), _) => client.clone(),
+        (None, Some(proxy)) => Client::builder().proxy(proxy.web_proxy()?).build()?,
+        (None, None) => Client::default(),
+    };
+    let cell = options.cell;
+
+    let (mut servers, mut ws_servers) = match options.strategy {
+        SelectionStrategy::Load => {
+            let response: ConnectListResponse = client
+                .get(format!(
+                    "https://api.steampowered.com/ISteamDirectory/GetCMListForConnect/v1/?cellid={cell}"
+                ))
+                .send()
+                .await?
+                .json()
+                .await?;
+            response.by_load()
+        }
+        _ => {
+            let response: ServerListResponse = client
+                .get(format!(
+                    "https://api.steampowered.com/ISteamDirectory/GetCMList/v1/?cellid={cell}"
+                ))
+                .send()
+                .await?
+                .json()
+                .await?;
+            (
+                response.response.server_list,
+                response.response.server_list_websockets,
+            )
+        }
+    };
+    if servers.is_empty() {
+        return Err(ServerDiscoveryError::NoServers);
+    }
+    if ws_servers.is_empty() {
+        return Err(ServerDiscoveryError::NoWsServers);
+    }
+
+    if let SelectionStrategy::Latency { candidates } = options.strategy {
         servers.shuffle(&mut thread_rng());
         ws_servers.shuffle(&mut thread_rng());
+        (servers, ws_servers) = join(
+            rank_by_latency(servers, candidates, options.proxy.as_ref(), |addr| {
+                (addr.ip().to_string(), addr.port())
+            }),
+            rank_by_latency(ws_servers, candidates, options.proxy.as_ref(), |addr| {
+                split_host_port(addr)
+            }),
+        )
+        .await;
+    }
 
-        ServerList {
-            servers: Arc::new(Mutex::new(servers.into_iter().cycle())),
-            ws_servers: Arc::new(Mutex::new(ws_servers.into_iter().cycle())),
+    Ok(ServerAddresses {
+        servers,
+        ws_servers,
+    })
+}
+
+/// Read a cached server list, with the time since it was cached
+async fn read_cache(path: &Path) -> Option<(ServerAddresses, Duration)> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!(error = ?e, path = ?path, "failed to read server list cache");
+            return None;
         }
+    };
+    let cache: CacheFile = match serde_json::from_slice(&content) {
+        Ok(cache) => cache,
+        Err(e) => {
+            warn!(error = ?e, path = ?path, "invalid server list cache");
+            return None;
+        }
+    };
+    let age = (UNIX_EPOCH + Duration::from_secs(cache.fetched_at))
+        .elapsed()
+        .unwrap_or_default();
+    Some((cache.addresses, age))
+}
+
+async fn write_cache(path: &Path, addresses: &ServerAddresses) {
+    let cache = CacheFile {
+        fetched_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        addresses: addresses.clone(),
+    };
+    if let Err(e) = write_file_atomic(path, &cache).await {
+        warn!(error = ?e, path = ?path, "failed to write server list cache");
+    }
+}
+
+/// Write to a temporary file next to the target and move it into place,
+/// so readers never see a partially written file
+async fn write_file_atomic(path: &Path, cache: &CacheFile) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{:08x}.tmp", random::<u32>()));
+    let temp_path = path.with_file_name(temp_name);
+    fs::write(&temp_path, serde_json::to_vec(cache)?).await?;
+    if let Err(e) = fs::rename(&temp_path, path).await {
+        fs::remove_file(&temp_path).await.ok();
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ServerAddresses {
+    servers: Vec<SocketAddr>,
+    ws_servers: Vec<String>,
+}
+
+impl From<ServerList> for ServerAddresses {
+    fn from(list: ServerList) -> Self {
+        ServerAddresses {
+            servers: list.servers.lock().unwrap().servers.clone(),
+            ws_servers: list.ws_servers.lock().unwrap().servers.clone(),
+        }
+    }
+}
+
+impl TryFrom<ServerAddresses> for ServerList {
+    type Error = ServerDiscoveryError;
+
+    fn try_from(addresses: ServerAddresses) -> Result<Self, Self::Error> {
+        ServerList::from_addresses(addresses.servers, addresses.ws_servers)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    /// Unix timestamp of when the list was discovered
+    fetched_at: u64,
+    #[serde(flatten)]
+    addresses: ServerAddresses,
+}
+
+/// The websocket url for a server address, addresses that already include a scheme are used as is
+pub(crate) fn ws_url(addr: &str) -> String {
+    if addr.contains("://") {
+        addr.to_string()
+    } else {
+        format!("wss://{addr}/cmsocket/")
+    }
+}
+
+#[derive(Debug)]
+struct ServerPool<T> {
+    /// Servers ordered by preference
+    servers: Vec<T>,
+    rotate: bool,
+    next: usize,
+    cooling_down: HashMap<T, Instant>,
+}
+
+impl<T: Clone + Eq + Hash> ServerPool<T> {
+    /// Create a pool from servers ordered by preference, or shuffle them if they should be rotated
+    fn new(mut servers: Vec<T>, rotate: bool) -> Self {
+        if rotate {
+            servers.shuffle(&mut thread_rng());
+        }
+        ServerPool {
+            servers,
+            rotate,
+            next: 0,
+            cooling_down: HashMap::new(),
+        }
+    }
+
+    /// Pick up to `count` different servers, in order of preference
+    fn pick_many(&mut self, count: usize) -> Vec<T> {
+        let now = Instant::now();
+        self.cooling_down.retain(|_, until| *until > now);
+
+        let start = if self.rotate { self.next } else { 0 };
+        let len = self.servers.len();
+        let available: Vec<usize> = (0..len)
+            .map(|offset| (start + offset) % len)
+            .filter(|index| !self.cooling_down.contains_key(&self.servers[*index]))
+            .take(count.max(1))
+            .collect();
+        match available.last() {
+            Some(last) => {
+                self.next = last + 1;
+                available
+                    .into_iter()
+                    .map(|index| self.servers[index].clone())
+                    .collect()
+            }
+            // every server is cooling down, pick the one that will be available first
+            // `unwrap` is safe as `discover_with` already checks for servers being present.
+            None => vec![self
+                .servers
+                .iter()
+                .min_by_key(|server| self.cooling_down.get(*server))
+                .unwrap()
+                .clone()],
+        }
+    }
+
+    fn replace(&mut self, mut servers: Vec<T>) {
+        if !servers.is_empty() {
+            if self.rotate {
+                servers.shuffle(&mut thread_rng());
+            }
+            self.servers = servers;
+            self.next = 0;
+        }
+    }
+
+    /// Put the servers in front of the list, keeping the existing servers as fallback
+    fn merge(&mut self, mut servers: Vec<T>) {
+        if servers.is_empty() {
+            return;
+        }
+        if self.rotate {
+            servers.shuffle(&mut thread_rng());
+        }
+        let existing: Vec<T> = std::mem::take(&mut self.servers)
+            .into_iter()
+            .filter(|server| !servers.contains(server))
+            .collect();
+        servers.extend(existing);
+        self.servers = servers;
+        self.next = 0;
+    }
+
+    fn cool_down(&mut self, server: T, duration: Duration) {
+        self.cooling_down.insert(server, Instant::now() + duration);
+    }
+}
+
+/// Split a `host:port` websocket server address, defaulting to the https port
+fn split_host_port(addr: &str) -> (String, u16) {
+    match addr
+        .rsplit_once(':')
+        .map(|(host, port)| (host, port.parse()))
+    {
+        Some((host, Ok(port))) => (host.to_string(), port),
+        _ => (addr.to_string(), 443),
+    }
+}
+
+/// Move the candidates with the lowest connect time to the front of the list
+///
+/// The probes are made through the proxy if one is set, so the servers never see our own address.
+async fn rank_by_latency<T: Debug>(
+    mut servers: Vec<T>,
+    candidates: usize,
+    proxy: Option<&Proxy>,
+    addr: impl Fn(&T) -> (String, u16),
+) -> Vec<T> {
+    let candidates = candidates.min(servers.len());
+    let rest = servers.split_off(candidates);
+    let probes = servers.iter().map(|server| {
+        let (host, port) = addr(server);
+        async move {
+            let start = Instant::now();
+            let connected = match proxy {
+                Some(proxy) => timeout(PROBE_TIMEOUT, proxy.connect(&host, port))
+                    .await
+                    .is_ok_and(|result| result.is_ok()),
+                None => timeout(PROBE_TIMEOUT, TcpStream::connect((host.as_str(), port)))
+                    .await
+                    .is_ok_and(|result| result.is_ok()),
+            };
+            connected.then(|| start.elapsed())
+        }
+    });
+    let latencies = join_all(probes).await;
+    let mut ranked: Vec<_> = servers.into_iter().zip(latencies).collect();
+    // servers that failed to respond are sorted after the ones that did
+    ranked.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+    for (server, latency) in &ranked {
+        debug!(server = ?server, latency = ?latency, "probed server");
     }
+    ranked
+        .into_iter()
+        .map(|(server, _)| server)
+        .chain(rest)
+        .collect()
 }
 
 #[derive(Debug, Deserialize)]
@@ -133,3 +624,106 @@ struct ServerListResponseInner {
     #[serde(rename = "serverlist_websockets")]
     server_list_websockets: Vec<String>,
 }
+
+#[derive(Debug, Deserialize)]
+struct ConnectListResponse {
+    response: ConnectListResponseInner,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectListResponseInner {
+    #[serde(rename = "serverlist")]
+    server_list: Vec<ConnectListServer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConnectListServer {
+    endpoint: String,
+    legacy_endpoint: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    load: f32,
+    wtd_load: Option<f32>,
+}
+
+impl ConnectListResponse {
+    /// Split the servers into tcp and websocket servers, ordered by their weighted load
+    fn by_load(self) -> (Vec<SocketAddr>, Vec<String>) {
+        let mut servers = self.response.server_list;
+        servers.sort_by(|a, b| {
+            a.wtd_load
+                .unwrap_or(a.load)
+                .total_cmp(&b.wtd_load.unwrap_or(b.load))
+        });
+        let mut tcp_servers = Vec::new();
+        let mut ws_servers = Vec::new();
+        for server in servers {
+            match server.kind.as_str() {
+                "netfilter" => {
+                    if let Some(addr) = server.legacy_endpoint.and_then(|addr| addr.parse().ok()) {
+                        tcp_servers.push(addr);
+                    }
+                }
+                "websockets" => ws_servers.push(server.endpoint),
+                _ => {}
+            }
+        }
+        (tcp_servers, ws_servers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_in_order_of_preference() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], false);
+        assert_eq!(pool.pick_many(2), vec![1, 2]);
+        assert_eq!(pool.pick_many(1), vec![1]);
+        assert_eq!(pool.pick_many(5), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rotate_through_servers() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], true);
+        let first = pool.pick_many(1);
+        let second = pool.pick_many(1);
+        let third = pool.pick_many(1);
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        assert_eq!(pool.pick_many(1), first);
+    }
+
+    #[test]
+    fn skip_cooling_down_servers() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], false);
+        pool.cool_down(1, Duration::from_secs(60));
+        assert_eq!(pool.pick_many(3), vec![2, 3]);
+    }
+
+    #[test]
+    fn cool_down_expires() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], false);
+        pool.cool_down(1, Duration::ZERO);
+        assert_eq!(pool.pick_many(1), vec![1]);
+        assert!(pool.cooling_down.is_empty());
+    }
+
+    #[test]
+    fn all_cooling_down_picks_first_available() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], false);
+        pool.cool_down(1, Duration::from_secs(60));
+        pool.cool_down(2, Duration::from_secs(10));
+        pool.cool_down(3, Duration::from_secs(30));
+        assert_eq!(pool.pick_many(3), vec![2]);
+    }
+
+    #[test]
+    fn merge_puts_new_servers_first() {
+        let mut pool = ServerPool::new(vec![1, 2, 3], false);
+        pool.merge(vec![4, 2]);
+        assert_eq!(pool.servers, vec![4, 2, 1, 3]);
+    }
+}
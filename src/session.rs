@@ -9,6 +9,7 @@ use crate::proto::steammessages_clientserver_login::{
 };
 use crate::NetMessage;
 use protobuf::MessageField;
+use std::fmt::{Debug, Formatter};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
@@ -58,6 +59,8 @@ pub enum LoginError {
     UnavailableAccount,
     #[error("rate limited")]
     RateLimited,
+    #[error("steam asked to connect to another server")]
+    TryAnotherCM,
 }
 
 impl From<EResult> for LoginError {
@@ -73,6 +76,7 @@ impl From<EResult> for LoginError {
             | EResult::LimitExceeded
             | EResult::AccountLimitExceeded => LoginError::RateLimited,
             EResult::AccountLoginDeniedNeedTwoFactor => LoginError::SteamGuardRequired,
+            EResult::TryAnotherCM => LoginError::TryAnotherCM,
             value => LoginError::Unknown(value),
         }
     }
@@ -144,6 +148,46 @@ impl Session {
     }
 }
 
+/// The credentials used to start a session, kept so the session can be started again after reconnecting
+#[derive(Clone)]
+pub(crate) enum Credentials {
+    Anonymous(AccountType),
+    Token {
+        account: String,
+        steam_id: SteamID,
+        token: String,
+    },
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credentials::Anonymous(account_type) => {
+                f.debug_tuple("Anonymous").field(account_type).finish()
+            }
+            Credentials::Token {
+                account, steam_id, ..
+            } => f
+                .debug_struct("Token")
+                .field("account", account)
+                .field("steam_id", steam_id)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+/// Start a session using previously used credentials
+pub async fn logon(connection: &mut RawConnection, credentials: &Credentials) -> Result<Session> {
+    match credentials {
+        Credentials::Anonymous(account_type) => anonymous(connection, *account_type).await,
+        Credentials::Token {
+            account,
+            steam_id,
+            token,
+        } => login(connection, account, *steam_id, token).await,
+    }
+}
+
 pub async fn anonymous(connection: &RawConnection, account_type: AccountType) -> Result<Session> {
     let mut ip = CMsgIPAddress::new();
     ip.set_v4(0);
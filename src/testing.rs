@@ -0,0 +1,518 @@
+//! A local mock of the steam connection manager servers for testing applications without connecting to steam
+//!
+//! The [`MockCmServer`] listens on localhost and speaks either the websocket or the tcp protocol. It handles the
+//! encryption handshake, logons and heartbeats itself, service method calls are answered by handlers registered
+//! with [`MockCmServer::add_handler`].
+//!
+//! Connect to the mock server with its [`server_list`](MockCmServer::server_list) and
+//! [`transport_config`](MockCmServer::transport_config).
+//!
+//! This module is only available with the `testing` feature.
+
+use crate::eresult::EResult;
+use crate::message::{
+    encode_multi, flatten_multi, ChannelEncryptRequest, ChannelEncryptResult,
+    ClientEncryptResponse, EncodedBody, NetMessage, ServiceMethodMessage,
+};
+use crate::net::{NetMessageHeader, NetworkError, ParseLimits, RawNetMessage};
+use crate::service_method::ServiceMethodRequest;
+use crate::transport::tcp::{encode_message, FrameCodec};
+use crate::transport::{Transport, TransportConfig};
+use crate::ServerList;
+use bytes::{BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use futures_util::future::BoxFuture;
+use futures_util::{FutureExt, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use protobuf::MessageField;
+use rand::random;
+use rsa::RsaPrivateKey;
+use std::future::{ready, Future};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::pin;
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use steam_vent_crypto::{decrypt_with_key, symmetric_decrypt, symmetric_encrypt};
+use steam_vent_proto::enums_clientserver::EMsg;
+use steam_vent_proto::steammessages_base::CMsgIPAddress;
+use steam_vent_proto::steammessages_clientserver_login::{
+    CMsgClientHeartBeat, CMsgClientLoggedOff, CMsgClientLogon, CMsgClientLogonResponse,
+};
+use steam_vent_proto::RpcMessage;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::{select, spawn};
+use tokio_tungstenite::accept_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, instrument, warn};
+
+type Result<T, E = NetworkError> = std::result::Result<T, E>;
+
+type Handler = Box<dyn Fn(Bytes) -> BoxFuture<'static, Result<Bytes, EResult>> + Send + Sync>;
+
+const DEFAULT_HEARTBEAT_SECONDS: i32 = 9;
+
+/// The key used for the tcp encryption handshake, shared by all mock servers since generating it is slow
+fn encryption_key() -> &'static RsaPrivateKey {
+    static KEY: OnceLock<RsaPrivateKey> = OnceLock::new();
+    KEY.get_or_init(|| {
+        RsaPrivateKey::new(&mut rand::thread_rng(), 1024)
+            .expect("failed to generate mock server key")
+    })
+}
+
+/// A mock connection manager server listening on localhost
+///
+/// All connected clients are served until the mock server is dropped.
+pub struct MockCmServer {
+    addr: SocketAddr,
+    transport: Transport,
+    state: Arc<ServerState>,
+    shutdown: CancellationToken,
+}
+
+struct ServerState {
+    handlers: DashMap<&'static str, Handler>,
+    clients: DashMap<u64, mpsc::Sender<ClientCommand>>,
+    next_client: AtomicU64,
+    next_session: AtomicI32,
+    next_account: AtomicU32,
+    logon_result: Mutex<EResult>,
+    heartbeat_seconds: AtomicI32,
+}
+
+enum ClientCommand {
+    Send(RawNetMessage),
+    Disconnect,
+}
+
+impl MockCmServer {
+    /// Start a mock server on a random localhost port
+    pub async fn start(transport: Transport) -> std::io::Result<Self> {
+        if transport == Transport::Tcp {
+            encryption_key();
+        }
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(ServerState {
+            handlers: DashMap::new(),
+            clients: DashMap::new(),
+            next_client: AtomicU64::new(0),
+            next_session: AtomicI32::new(1),
+            next_account: AtomicU32::new(1),
+            logon_result: Mutex::new(EResult::OK),
+            heartbeat_seconds: AtomicI32::new(DEFAULT_HEARTBEAT_SECONDS),
+        });
+        let shutdown = CancellationToken::new();
+        spawn(listen(listener, transport, state.clone(), shutdown.clone()));
+        debug!(%addr, ?transport, "mock server started");
+        Ok(MockCmServer {
+            addr,
+            transport,
+            state,
+            shutdown,
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A server list containing only the mock server
+    pub fn server_list(&self) -> ServerList {
+        ServerList::from_addresses([self.addr], [format!("ws://{}/cmsocket/", self.addr)])
+            .expect("server list with addresses")
+    }
+
+    /// The transport config needed to connect to the mock server
+    pub fn transport_config(&self) -> TransportConfig {
+        self.configure(TransportConfig::default())
+    }
+
+    /// Adjust an existing transport config to connect to the mock server
+    pub fn configure(&self, config: TransportConfig) -> TransportConfig {
+        config
+            .with_transport(self.transport)
+            .with_encryption_key(encryption_key().to_public_key())
+    }
+
+    /// Answer calls to a service method with a handler
+    ///
+    /// Calls to methods without a handler are answered with [`EResult::Fail`]. A handler that never completes
+    /// can be used to test timeouts.
+    pub fn add_handler<Req, F, Fut>(&self, handler: F)
+    where
+        Req: ServiceMethodRequest,
+        F: Fn(Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Req::Response, EResult>> + Send + 'static,
+    {
+        let handler = move |body: Bytes| match Req::parse(&mut body.as_ref()) {
+            Ok(request) => handler(request)
+                .map(|result| {
+                    let response = result?;
+                    let mut body = BytesMut::new().writer();
+                    RpcMessage::write(&response, &mut body).map_err(|_| EResult::Fail)?;
+                    Ok(body.into_inner().freeze())
+                })
+                .boxed(),
+            Err(e) => {
+                warn!(error = ?e, method = Req::REQ_NAME, "received malformed request");
+                ready(Err(EResult::InvalidParam)).boxed()
+            }
+        };
+        self.state.handlers.insert(Req::REQ_NAME, Box::new(handler));
+    }
+
+    /// Set the result returned for logon requests, defaults to [`EResult::OK`]
+    pub fn set_logon_result(&self, result: EResult) {
+        *self.state.logon_result.lock().unwrap() = result;
+    }
+
+    /// Set the heartbeat interval requested from clients on logon, defaults to 9 seconds
+    pub fn set_heartbeat_seconds(&self, seconds: i32) {
+        self.state
+            .heartbeat_seconds
+            .store(seconds, Ordering::Relaxed);
+    }
+
+    /// The number of currently connected clients
+    pub fn connection_count(&self) -> usize {
+        self.state.clients.len()
+    }
+
+    /// Send a message to all connected clients
+    pub async fn send<T: NetMessage>(&self, message: T) {
+        let message = RawNetMessage::from_message(NetMessageHeader::default(), message)
+            .expect("failed to encode message");
+        self.send_raw(message).await
+    }
+
+    /// Send a service method notification to all connected clients
+    pub async fn notify<Req: ServiceMethodRequest>(&self, notification: Req) {
+        let message = RawNetMessage::from_message_with_kind(
+            NetMessageHeader::default(),
+            ServiceMethodMessage(notification),
+            EMsg::k_EMsgServiceMethod,
+            true,
+        )
+        .expect("failed to encode notification");
+        self.send_raw(message).await
+    }
+
+    /// Send multiple messages combined into a single multi message to all connected clients
+    pub async fn send_multi(
+        &self,
+        messages: impl IntoIterator<Item = RawNetMessage>,
+        compress: bool,
+    ) {
+        let multi = encode_multi(messages, compress.then_some(0)).expect("failed to encode multi");
+        let message = RawNetMessage::from_message(NetMessageHeader::default(), multi)
+            .expect("failed to encode multi");
+        self.send_raw(message).await
+    }
+
+    /// Send an encoded message to all connected clients
+    pub async fn send_raw(&self, message: RawNetMessage) {
+        for client in self.clients() {
+            client.send(ClientCommand::Send(message.clone())).await.ok();
+        }
+    }
+
+    /// Close the connections to all connected clients
+    pub async fn disconnect(&self) {
+        for client in self.clients() {
+            client.send(ClientCommand::Disconnect).await.ok();
+        }
+    }
+
+    fn clients(&self) -> Vec<mpsc::Sender<ClientCommand>> {
+        self.state
+            .clients
+            .iter()
+            .map(|client| client.value().clone())
+            .collect()
+    }
+}
+
+impl Drop for MockCmServer {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+async fn listen(
+    listener: TcpListener,
+    transport: Transport,
+    state: Arc<ServerState>,
+    shutdown: CancellationToken,
+) {
+    loop {
+        let stream = select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!(error = ?e, "mock server failed to accept connection");
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        spawn(async move {
+            let result = match transport {
+                Transport::WebSocket => match accept_websocket(stream).await {
+                    Ok((sender, receiver)) => serve(sender, receiver, &state, shutdown).await,
+                    Err(e) => Err(e),
+                },
+                Transport::Tcp => match accept_tcp(stream).await {
+                    Ok((sender, receiver)) => serve(sender, receiver, &state, shutdown).await,
+                    Err(e) => Err(e),
+                },
+            };
+            if let Err(e) = result {
+                debug!(error = ?e, "mock client connection failed");
+            }
+        });
+    }
+    debug!("mock server stopped");
+}
+
+async fn accept_websocket(
+    stream: TcpStream,
+) -> Result<(
+    impl Sink<RawNetMessage, Error = NetworkError>,
+    impl Stream<Item = Result<RawNetMessage>>,
+)> {
+    let (sender, receiver) = accept_async(stream).await?.split();
+    Ok((
+        sender
+            .sink_map_err(NetworkError::from)
+            .with(|message: RawNetMessage| ready(Ok(WsMessage::binary(message.into_bytes())))),
+        flatten_multi(
+            receiver
+                .map_err(NetworkError::from)
+                .try_filter_map(|message| {
+                    ready(match message {
+                        WsMessage::Binary(data) => RawNetMessage::read(data).map(Some),
+                        _ => Ok(None),
+                    })
+                }),
+            ParseLimits::default(),
+        ),
+    ))
+}
+
+/// Accept a tcp connection, acting as the server side of the encryption handshake
+#[instrument(skip(stream))]
+async fn accept_tcp(
+    stream: TcpStream,
+) -> Result<(
+    impl Sink<RawNetMessage, Error = NetworkError>,
+    impl Stream<Item = Result<RawNetMessage>>,
+)> {
+    let limits = ParseLimits::default();
+    let (read, write) = stream.into_split();
+    let mut raw_reader = FramedRead::new(read, FrameCodec { limits });
+    let mut raw_writer = FramedWrite::new(write, FrameCodec { limits });
+
+    let request = ChannelEncryptRequest {
+        protocol: 1,
+        universe: 1,
+        nonce: random(),
+    };
+    encode_message(&NetMessageHeader::default(), &request, &mut raw_writer).await?;
+
+    let response = RawNetMessage::read(raw_reader.next().await.ok_or(NetworkError::EOF)??)?
+        .into_message::<ClientEncryptResponse>()?;
+    let key: [u8; 32] = decrypt_with_key(encryption_key(), &response.encrypted_key)?
+        .try_into()
+        .map_err(|_| NetworkError::CryptoHandshakeFailed)?;
+
+    let result = ChannelEncryptResult { result: 1 };
+    encode_message(&NetMessageHeader::default(), &result, &mut raw_writer).await?;
+    debug!("crypt handshake complete");
+
+    Ok((
+        raw_writer.with(move |message: RawNetMessage| {
+            ready(Ok(symmetric_encrypt(message.into_bytes(), &key)))
+        }),
+        flatten_multi(
+            raw_reader
+                .and_then(move |encrypted| {
+                    ready(symmetric_decrypt(encrypted, &key).map_err(Into::into))
+                })
+                .and_then(|raw| ready(RawNetMessage::read(raw))),
+            limits,
+        ),
+    ))
+}
+
+/// Handle the messages from a single client until the connection is closed
+async fn serve<
+    Sender: Sink<RawNetMessage, Error = NetworkError>,
+    Receiver: Stream<Item = Result<RawNetMessage>>,
+>(
+    sender: Sender,
+    receiver: Receiver,
+    state: &ServerState,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut sender = pin!(sender);
+    let mut receiver = pin!(receiver);
+    let (commands_tx, mut commands) = mpsc::channel(16);
+    let id = state.next_client.fetch_add(1, Ordering::Relaxed);
+    state.clients.insert(id, commands_tx.clone());
+
+    let result = loop {
+        select! {
+            command = commands.recv() => match command {
+                Some(ClientCommand::Send(message)) => {
+                    if let Err(e) = sender.send(message).await {
+                        break Err(e);
+                    }
+                }
+                Some(ClientCommand::Disconnect) | None => break Ok(()),
+            },
+            message = receiver.next() => match message {
+                Some(Ok(message)) if message.kind == EMsg::k_EMsgClientLogOff => {
+                    debug!("client logged off");
+                    let logged_off = CMsgClientLoggedOff {
+                        eresult: Some(EResult::OK as i32),
+                        ..CMsgClientLoggedOff::default()
+                    };
+                    if let Ok(message) = RawNetMessage::from_message(NetMessageHeader::default(), logged_off) {
+                        sender.send(message).await.ok();
+                    }
+                    break Ok(());
+                }
+                Some(Ok(message)) => {
+                    if let Some(reply) = state.respond(message, &commands_tx) {
+                        if let Err(e) = sender.send(reply).await {
+                            break Err(e);
+                        }
+                    }
+                }
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            _ = shutdown.cancelled() => break Ok(()),
+        }
+    };
+
+    state.clients.remove(&id);
+    sender.close().await.ok();
+    result
+}
+
+impl ServerState {
+    /// Handle a message from a client, returning the reply if it can be sent immediately
+    fn respond(
+        &self,
+        message: RawNetMessage,
+        client: &mpsc::Sender<ClientCommand>,
+    ) -> Option<RawNetMessage> {
+        let reply = if message.kind == EMsg::k_EMsgClientLogon {
+            match message.into_header_and_message::<CMsgClientLogon>() {
+                Ok((header, logon)) => {
+                    let (header, response) = self.logon(header, logon);
+                    RawNetMessage::from_message(header, response)
+                }
+                Err(e) => Err(e),
+            }
+        } else if message.kind == EMsg::k_EMsgClientHeartBeat {
+            match message.into_message::<CMsgClientHeartBeat>() {
+                Ok(heartbeat) if heartbeat.send_reply() => RawNetMessage::from_message(
+                    NetMessageHeader::default(),
+                    CMsgClientHeartBeat::default(),
+                ),
+                _ => return None,
+            }
+        } else if message.kind == EMsg::k_EMsgServiceMethodCallFromClient
+            || message.kind == EMsg::k_EMsgServiceMethodCallFromClientNonAuthed
+        {
+            self.call(message, client);
+            return None;
+        } else {
+            debug!(kind = ?message.kind, "mock server ignoring message");
+            return None;
+        };
+        match reply {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                warn!(error = ?e, "mock server failed to encode reply");
+                None
+            }
+        }
+    }
+
+    /// The response to a logon, with the header containing the steam id and session for the client
+    fn logon(
+        &self,
+        header: NetMessageHeader,
+        logon: CMsgClientLogon,
+    ) -> (NetMessageHeader, CMsgClientLogonResponse) {
+        let mut steam_id = header.steam_id;
+        if logon.access_token.is_none() && steam_id.account_id() == 0 {
+            steam_id.set_account_id(self.next_account.fetch_add(1, Ordering::Relaxed));
+        }
+        let result = *self.logon_result.lock().unwrap();
+        debug!(
+            steam_id = u64::from(steam_id),
+            account = logon.account_name(),
+            ?result,
+            "client logon"
+        );
+
+        let mut ip = CMsgIPAddress::new();
+        ip.set_v4(Ipv4Addr::LOCALHOST.into());
+        let response = CMsgClientLogonResponse {
+            eresult: Some(result as i32),
+            heartbeat_seconds: Some(self.heartbeat_seconds.load(Ordering::Relaxed)),
+            public_ip: MessageField::some(ip),
+            ..CMsgClientLogonResponse::default()
+        };
+        let header = NetMessageHeader {
+            steam_id,
+            session_id: self.next_session.fetch_add(1, Ordering::Relaxed),
+            ..NetMessageHeader::default()
+        };
+        (header, response)
+    }
+
+    /// Run the handler for a service method call, sending the response once the handler completes
+    fn call(&self, message: RawNetMessage, client: &mpsc::Sender<ClientCommand>) {
+        let name = message.header.target_job_name.clone().unwrap_or_default();
+        let response = match self.handlers.get(name.as_ref()) {
+            Some(handler) => handler(message.data),
+            None => {
+                warn!(method = %name, "no handler registered for service method");
+                ready(Err(EResult::Fail)).boxed()
+            }
+        };
+        let job_id = message.header.source_job_id;
+        let client = client.clone();
+        spawn(async move {
+            let result = response.await;
+            let header = NetMessageHeader {
+                target_job_id: job_id,
+                target_job_name: Some(name),
+                result: Some(result.as_ref().err().copied().unwrap_or(EResult::OK) as i32),
+                ..NetMessageHeader::default()
+            };
+            let body = EncodedBody::from(result.unwrap_or_default());
+            match RawNetMessage::from_message_with_kind(
+                header,
+                body,
+                EMsg::k_EMsgServiceMethodResponse,
+                true,
+            ) {
+                Ok(reply) => {
+                    client.send(ClientCommand::Send(reply)).await.ok();
+                }
+                Err(e) => warn!(error = ?e, "mock server failed to encode response"),
+            }
+        });
+    }
+}
@@ -0,0 +1,57 @@
+use crate::connection::ConnectionTrait;
+use crate::net::NetworkError;
+use crate::service_method::ServiceMethodRequest;
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// Expose the rpc-requests of a connection as a [`tower_service::Service`]
+///
+/// The service is ready as long as the connection is open, once the connection is closed
+/// `poll_ready` returns [`NetworkError::ConnectionClosed`].
+#[derive(Clone)]
+pub struct RpcService<C> {
+    connection: C,
+}
+
+impl<C> Debug for RpcService<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcService").finish_non_exhaustive()
+    }
+}
+
+impl<C: ConnectionTrait> RpcService<C> {
+    pub fn new(connection: C) -> Self {
+        RpcService { connection }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.connection
+    }
+}
+
+impl<C, Req> Service<Req> for RpcService<C>
+where
+    C: ConnectionTrait + Clone + Send + Sync + 'static,
+    Req: ServiceMethodRequest + Send + 'static,
+    Req::Response: Send,
+{
+    type Response = Req::Response;
+    type Error = NetworkError;
+    type Future = Pin<Box<dyn Future<Output = Result<Req::Response, NetworkError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.connection.is_connected() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Ready(Err(NetworkError::ConnectionClosed))
+        }
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let connection = self.connection.clone();
+        Box::pin(async move { connection.service_method(req).await })
+    }
+}
@@ -1,9 +1,203 @@
+use crate::net::NetworkError;
 use bytes::BytesMut;
+use rsa::RsaPublicKey;
+use std::future::Future;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 
-#[allow(dead_code)]
+mod parallel;
+mod proxy;
 pub mod tcp;
+mod tls;
 pub mod websocket;
 
+pub(crate) use parallel::connect_parallel;
+pub use proxy::{Proxy, ProxyError};
+pub use tls::TlsConfig;
+
+/// The protocol used to connect to the steam servers
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Transport {
+    /// Websocket over TLS, using the websocket servers from the server list
+    #[default]
+    WebSocket,
+    /// Plain TCP with steam's own encryption handshake, using the tcp servers from the server list
+    Tcp,
+}
+
+/// Configuration for the transport used to connect to the steam servers
+#[derive(Debug, Default, Clone)]
+pub struct TransportConfig {
+    transport: Transport,
+    proxy: Option<Proxy>,
+    tls: TlsConfig,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    connect_candidates: usize,
+    attempt_delay: Option<Duration>,
+    connect_deadline: Option<Duration>,
+    ping_interval: Option<Duration>,
+    /// Key for the tcp encryption handshake, instead of the steam system key
+    #[cfg(feature = "testing")]
+    encryption_key: Option<RsaPublicKey>,
+}
+
+const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+impl TransportConfig {
+    pub fn with_transport(self, transport: Transport) -> Self {
+        TransportConfig { transport, ..self }
+    }
+
+    /// Connect to the steam servers through a proxy
+    pub fn with_proxy(self, proxy: Proxy) -> Self {
+        TransportConfig {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Set the TLS settings used by the websocket transport
+    pub fn with_tls(self, tls: TlsConfig) -> Self {
+        TransportConfig { tls, ..self }
+    }
+
+    /// Limit the time spent opening the tcp connection to the server or proxy
+    pub fn with_connect_timeout(self, connect_timeout: Duration) -> Self {
+        TransportConfig {
+            connect_timeout: Some(connect_timeout),
+            ..self
+        }
+    }
+
+    /// Limit the time spent on the TLS and websocket handshake, or the encryption handshake for the tcp transport
+    pub fn with_handshake_timeout(self, handshake_timeout: Duration) -> Self {
+        TransportConfig {
+            handshake_timeout: Some(handshake_timeout),
+            ..self
+        }
+    }
+
+    /// Connect to up to `candidates` servers in parallel, keeping the first one that completes the transport handshake
+    ///
+    /// Attempts are started with a staggered delay, see [`with_attempt_delay`](Self::with_attempt_delay).
+    pub fn with_parallel_connect(self, candidates: usize) -> Self {
+        TransportConfig {
+            connect_candidates: candidates,
+            ..self
+        }
+    }
+
+    /// Set the delay before starting a connection attempt to the next candidate, defaults to 250ms
+    pub fn with_attempt_delay(self, attempt_delay: Duration) -> Self {
+        TransportConfig {
+            attempt_delay: Some(attempt_delay),
+            ..self
+        }
+    }
+
+    /// Limit the total time spent connecting, including all parallel attempts
+    pub fn with_connect_deadline(self, connect_deadline: Duration) -> Self {
+        TransportConfig {
+            connect_deadline: Some(connect_deadline),
+            ..self
+        }
+    }
+
+    /// Send a websocket ping after the connection has been idle for this long, defaults to 30 seconds
+    pub fn with_ping_interval(self, ping_interval: Duration) -> Self {
+        TransportConfig {
+            ping_interval: Some(ping_interval),
+            ..self
+        }
+    }
+
+    /// Encrypt the session key for the tcp transport with a different key, used to connect to the mock server
+    #[cfg(feature = "testing")]
+    pub(crate) fn with_encryption_key(self, encryption_key: RsaPublicKey) -> Self {
+        TransportConfig {
+            encryption_key: Some(encryption_key),
+            ..self
+        }
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Open a tcp connection to a server, using the proxy if one is configured
+    pub(crate) async fn connect_tcp(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<TcpStream, NetworkError> {
+        let connect = async {
+            match &self.proxy {
+                Some(proxy) => proxy.connect(host, port).await,
+                None => Ok(TcpStream::connect((host, port)).await?),
+            }
+        };
+        with_timeout(self.connect_timeout, connect).await
+    }
+
+    pub(crate) fn connect_candidates(&self) -> usize {
+        self.connect_candidates.max(1)
+    }
+
+    #[cfg(feature = "testing")]
+    pub(crate) fn encryption_key(&self) -> Option<&RsaPublicKey> {
+        self.encryption_key.as_ref()
+    }
+
+    #[cfg(not(feature = "testing"))]
+    pub(crate) fn encryption_key(&self) -> Option<&RsaPublicKey> {
+        None
+    }
+
+    pub(crate) fn ping_interval(&self) -> Duration {
+        self.ping_interval.unwrap_or(DEFAULT_PING_INTERVAL)
+    }
+
+    pub(crate) fn attempt_delay(&self) -> Duration {
+        self.attempt_delay.unwrap_or(DEFAULT_ATTEMPT_DELAY)
+    }
+
+    /// Run the full connection process, limited by the configured deadline
+    pub(crate) async fn with_deadline<T>(
+        &self,
+        connect: impl Future<Output = Result<T, NetworkError>>,
+    ) -> Result<T, NetworkError> {
+        with_timeout(self.connect_deadline, connect).await
+    }
+
+    pub(crate) fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    /// Run the transport handshake, limited by the configured handshake timeout
+    pub(crate) async fn handshake<T>(
+        &self,
+        handshake: impl Future<Output = Result<T, NetworkError>>,
+    ) -> Result<T, NetworkError> {
+        with_timeout(self.handshake_timeout, handshake).await
+    }
+}
+
+async fn with_timeout<T>(
+    duration: Option<Duration>,
+    fut: impl Future<Output = Result<T, NetworkError>>,
+) -> Result<T, NetworkError> {
+    match duration {
+        Some(duration) => timeout(duration, fut)
+            .await
+            .map_err(|_| NetworkError::Timeout)?,
+        None => fut.await,
+    }
+}
+
 /// Assert that two BytesMut can be unsplit without allocations
 #[track_caller]
 fn assert_can_unsplit(head: &BytesMut, tail: &BytesMut) {
@@ -0,0 +1,62 @@
+use crate::net::NetworkError;
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+use tokio::select;
+use tokio::time::sleep;
+use tracing::debug;
+
+/// Connect to the first responding candidate, starting a new attempt every `attempt_delay`
+/// or as soon as the previous attempt fails, as described in RFC 8305
+///
+/// An attempt succeeds once its transport handshake completes, the websocket upgrade or the channel encryption
+/// for tcp, both of which require an answer from the server. The `ClientHello` that follows has no reply from the
+/// server so it isn't part of the race, it is sent on the winning connection only.
+///
+/// Once an attempt succeeds, all other attempts are dropped and the candidate is returned with the connection.
+/// If every attempt fails, the last error is returned.
+pub(crate) async fn connect_parallel<A, T, Fut>(
+    candidates: Vec<A>,
+    attempt_delay: Duration,
+    connect: impl Fn(A) -> Fut,
+    on_failure: impl Fn(&A),
+) -> Result<(A, T), NetworkError>
+where
+    A: Clone + Debug,
+    Fut: Future<Output = Result<T, NetworkError>>,
+{
+    let mut candidates = candidates.into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut next = candidates.next();
+    let mut last_error = None;
+    loop {
+        if let Some(candidate) = next.take() {
+            debug!(candidate = ?candidate, "starting connection attempt");
+            let attempt = connect(candidate.clone());
+            attempts.push(async move { (candidate, attempt.await) });
+        }
+        if attempts.is_empty() {
+            return Err(last_error.unwrap_or(NetworkError::EOF));
+        }
+        let has_more = candidates.peek().is_some();
+        select! {
+            Some((candidate, result)) = attempts.next() => match result {
+                Ok(connection) => {
+                    debug!(candidate = ?candidate, "connection attempt succeeded");
+                    return Ok((candidate, connection));
+                }
+                Err(e) => {
+                    debug!(candidate = ?candidate, error = ?e, "connection attempt failed");
+                    on_failure(&candidate);
+                    last_error = Some(e);
+                    next = candidates.next();
+                }
+            },
+            _ = sleep(attempt_delay), if has_more => {
+                next = candidates.next();
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use crate::net::NetworkError;
+use base64::prelude::BASE64_STANDARD;
+use base64::Engine;
+use std::fmt::{Debug, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, instrument};
+
+type Result<T, E = NetworkError> = std::result::Result<T, E>;
+
+/// Error while establishing a connection through a proxy
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ProxyError {
+    #[error("proxy refused the connection: {0}")]
+    Refused(String),
+    #[error("proxy returned an invalid response")]
+    InvalidResponse,
+    #[error("proxy doesn't support any of the offered authentication methods")]
+    UnsupportedAuthentication,
+    #[error("proxy authentication failed")]
+    AuthenticationFailed,
+    #[error("socks5 username and password can't be longer than 255 bytes")]
+    InvalidCredentials,
+    #[error("socks5 host name can't be longer than 255 bytes")]
+    HostTooLong,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ProxyProtocol {
+    Http,
+    Socks5,
+}
+
+#[derive(Clone)]
+struct ProxyAuth {
+    username: String,
+    password: String,
+}
+
+/// A proxy to tunnel the connections to the steam servers through
+#[derive(Clone)]
+pub struct Proxy {
+    protocol: ProxyProtocol,
+    addr: String,
+    auth: Option<ProxyAuth>,
+}
+
+impl Debug for Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Proxy")
+            .field("protocol", &self.protocol)
+            .field("addr", &self.addr)
+            .field(
+                "username",
+                &self.auth.as_ref().map(|auth| auth.username.as_str()),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Proxy {
+    /// Use an http proxy, the connection is tunneled using the `CONNECT` method
+    ///
+    /// The address of the proxy is specified as `host:port`
+    pub fn http(addr: impl Into<String>) -> Self {
+        Proxy {
+            protocol: ProxyProtocol::Http,
+            addr: addr.into(),
+            auth: None,
+        }
+    }
+
+    /// Use a socks5 proxy
+    ///
+    /// The address of the proxy is specified as `host:port`
+    pub fn socks5(addr: impl Into<String>) -> Self {
+        Proxy {
+            protocol: ProxyProtocol::Socks5,
+            addr: addr.into(),
+            auth: None,
+        }
+    }
+
+    /// Authenticate to the proxy with a username and password
+    pub fn with_auth(self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        Proxy {
+            auth: Some(ProxyAuth {
+                username: username.into(),
+                password: password.into(),
+            }),
+            ..self
+        }
+    }
+
+    /// Open a tcp connection to the target through the proxy
+    #[instrument(skip(self), fields(proxy = self.addr))]
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.addr.as_str()).await?;
+        debug!("connected to proxy");
+        match self.protocol {
+            ProxyProtocol::Http => self.http_connect(&mut stream, host, port).await?,
+            ProxyProtocol::Socks5 => self.socks5_connect(&mut stream, host, port).await?,
+        }
+        debug!("proxy tunnel established");
+        Ok(stream)
+    }
+
+    fn http_request(&self, host: &str, port: u16) -> String {
+        let authority = match host.parse::<IpAddr>() {
+            // formats ipv6 addresses with brackets
+            Ok(ip) => SocketAddr::new(ip, port).to_string(),
+            Err(_) => format!("{host}:{port}"),
+        };
+        let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+        if let Some(auth) = &self.auth {
+            let credentials =
+                BASE64_STANDARD.encode(format!("{}:{}", auth.username, auth.password));
+            request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        request.push_str("\r\n");
+        request
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let request = self.http_request(host, port);
+        stream.write_all(request.as_bytes()).await?;
+
+        // read the response byte-by-byte so we don't consume anything past the headers
+        let mut reader = BufReader::with_capacity(1, stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).await?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(ProxyError::InvalidResponse.into());
+            }
+            if line == "\r\n" {
+                break;
+            }
+        }
+
+        let status = status.trim_end();
+        match status.split(' ').nth(1) {
+            Some("200") => Ok(()),
+            Some("407") => Err(ProxyError::AuthenticationFailed.into()),
+            Some(_) => Err(ProxyError::Refused(status.into()).into()),
+            None => Err(ProxyError::InvalidResponse.into()),
+        }
+    }
+
+    async fn socks5_connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        const VERSION: u8 = 5;
+        const NO_AUTH: u8 = 0;
+        const USERNAME_PASSWORD: u8 = 2;
+
+        // validate the lengths before talking to the proxy, they are sent as a single byte
+        let credentials = match &self.auth {
+            Some(auth) => Some((
+                socks5_length(&auth.username, ProxyError::InvalidCredentials)?,
+                socks5_length(&auth.password, ProxyError::InvalidCredentials)?,
+            )),
+            None => None,
+        };
+        let request = socks5_request(host, port)?;
+
+        match &self.auth {
+            Some(_) => stream.write_all(&[VERSION, 2, NO_AUTH, USERNAME_PASSWORD]),
+            None => stream.write_all(&[VERSION, 1, NO_AUTH]),
+        }
+        .await?;
+
+        let mut response = [0; 2];
+        stream.read_exact(&mut response).await?;
+        match (response, &self.auth, credentials) {
+            ([VERSION, NO_AUTH], _, _) => {}
+            (
+                [VERSION, USERNAME_PASSWORD],
+                Some(auth),
+                Some((username_length, password_length)),
+            ) => {
+                let mut request = vec![1, username_length];
+                request.extend_from_slice(auth.username.as_bytes());
+                request.push(password_length);
+                request.extend_from_slice(auth.password.as_bytes());
+                stream.write_all(&request).await?;
+
+                stream.read_exact(&mut response).await?;
+                if response[1] != 0 {
+                    return Err(ProxyError::AuthenticationFailed.into());
+                }
+            }
+            ([VERSION, _], _, _) => return Err(ProxyError::UnsupportedAuthentication.into()),
+            _ => return Err(ProxyError::InvalidResponse.into()),
+        }
+
+        stream.write_all(&request).await?;
+
+        let mut response = [0; 4];
+        stream.read_exact(&mut response).await?;
+        if response[0] != VERSION {
+            return Err(ProxyError::InvalidResponse.into());
+        }
+        if response[1] != 0 {
+            return Err(ProxyError::Refused(format!("socks error {}", response[1])).into());
+        }
+        // skip the bound address and port
+        let address_length = match response[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => return Err(ProxyError::InvalidResponse.into()),
+        };
+        let mut bound = vec![0; address_length + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    /// Get the proxy configuration for a web client
+    ///
+    /// Using a socks5 proxy for the web client requires the `socks` feature.
+    pub(crate) fn web_proxy(&self) -> reqwest::Result<reqwest::Proxy> {
+        let scheme = match self.protocol {
+            ProxyProtocol::Http => "http",
+            ProxyProtocol::Socks5 => "socks5h",
+        };
+        let proxy = reqwest::Proxy::all(format!("{scheme}://{}", self.addr))?;
+        Ok(match &self.auth {
+            Some(auth) => proxy.basic_auth(&auth.username, &auth.password),
+            None => proxy,
+        })
+    }
+}
+
+/// The socks5 `CONNECT` request for the target
+fn socks5_request(host: &str, port: u16) -> Result<Vec<u8>, ProxyError> {
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            request.push(3);
+            request.push(socks5_length(host, ProxyError::HostTooLong)?);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    Ok(request)
+}
+
+/// The length of a value in a socks5 request, which is limited to 255 bytes
+fn socks5_length(value: &str, error: ProxyError) -> Result<u8, ProxyError> {
+    u8::try_from(value.len()).map_err(|_| error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_request_authority() {
+        let proxy = Proxy::http("proxy:8080");
+        assert_eq!(
+            proxy.http_request("cm.steampowered.com", 443),
+            "CONNECT cm.steampowered.com:443 HTTP/1.1\r\nHost: cm.steampowered.com:443\r\n\r\n"
+        );
+        assert_eq!(
+            proxy.http_request("192.0.2.1", 27017),
+            "CONNECT 192.0.2.1:27017 HTTP/1.1\r\nHost: 192.0.2.1:27017\r\n\r\n"
+        );
+        assert_eq!(
+            proxy.http_request("2001:db8::1", 27017),
+            "CONNECT [2001:db8::1]:27017 HTTP/1.1\r\nHost: [2001:db8::1]:27017\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn http_request_auth() {
+        let proxy = Proxy::http("proxy:8080").with_auth("user", "pass");
+        assert_eq!(
+            proxy.http_request("192.0.2.1", 27017),
+            "CONNECT 192.0.2.1:27017 HTTP/1.1\r\nHost: 192.0.2.1:27017\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn socks5_request_address_types() {
+        assert_eq!(
+            socks5_request("192.0.2.1", 27017).unwrap(),
+            [5, 1, 0, 1, 192, 0, 2, 1, 0x69, 0x89]
+        );
+        assert_eq!(
+            socks5_request("2001:db8::1", 443).unwrap(),
+            [5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x01, 0xbb]
+        );
+        let mut domain = vec![5, 1, 0, 3, 7];
+        domain.extend_from_slice(b"example");
+        domain.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(socks5_request("example", 443).unwrap(), domain);
+        assert!(matches!(
+            socks5_request(&"a".repeat(256), 443),
+            Err(ProxyError::HostTooLong)
+        ));
+    }
+}
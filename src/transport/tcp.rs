@@ -1,18 +1,18 @@
 use crate::message::{
     flatten_multi, ChannelEncryptRequest, ChannelEncryptResult, ClientEncryptResponse, NetMessage,
 };
-use crate::net::{NetMessageHeader, NetworkError, RawNetMessage};
-use crate::transport::assert_can_unsplit;
-use bytemuck::{cast, Pod, Zeroable};
+use crate::net::{NetMessageHeader, NetworkError, ParseLimits, RawNetMessage, RESERVED_PREFIX};
+use crate::transport::{assert_can_unsplit, TransportConfig};
 use bytes::{Buf, BufMut, BytesMut};
 use futures_util::future::ready;
 use futures_util::{Sink, SinkExt, StreamExt, TryStreamExt};
 use std::convert::TryInto;
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use steam_vent_crypto::{
-    generate_session_key, symmetric_decrypt, symmetric_encrypt_with_iv_buffer,
+    generate_session_key, generate_session_key_with, symmetric_decrypt,
+    symmetric_encrypt_with_iv_buffer,
 };
-use tokio::net::{TcpStream, ToSocketAddrs};
 use tokio_stream::Stream;
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 use tracing::{debug, instrument, trace};
@@ -21,14 +21,21 @@ type Result<T, E = NetworkError> = std::result::Result<T, E>;
 
 const MAGIC: [u8; 4] = *b"VT01";
 
-#[derive(Debug, Default, Copy, Clone, Zeroable, Pod)]
-#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct Header {
     length: u32,
     magic: [u8; 4],
 }
 
 impl Header {
+    pub fn read(bytes: [u8; 8]) -> Self {
+        let [l0, l1, l2, l3, m0, m1, m2, m3] = bytes;
+        Header {
+            length: u32::from_le_bytes([l0, l1, l2, l3]),
+            magic: [m0, m1, m2, m3],
+        }
+    }
+
     pub fn validate(&self) -> Result<()> {
         if self.magic != MAGIC {
             Err(NetworkError::InvalidHeader)
@@ -38,7 +45,9 @@ impl Header {
     }
 }
 
-struct FrameCodec;
+pub(crate) struct FrameCodec {
+    pub(crate) limits: ParseLimits,
+}
 
 impl Decoder for FrameCodec {
     type Item = BytesMut;
@@ -50,9 +59,16 @@ impl Decoder for FrameCodec {
         }
 
         let header_bytes = src[0..8].try_into().unwrap();
-        let header = cast::<[u8; 8], Header>(header_bytes);
+        let header = Header::read(header_bytes);
         header.validate()?;
         trace!("got header for packet of {} bytes", header.length);
+        let limit = self.limits.max_frame_size();
+        if header.length as usize > limit {
+            return Err(NetworkError::FrameTooLarge {
+                size: header.length as usize,
+                limit,
+            });
+        }
 
         if src.len() < 8 + header.length as usize {
             return Ok(None);
@@ -83,21 +99,12 @@ struct RawMessageEncoder {
 impl Encoder<RawNetMessage> for RawMessageEncoder {
     type Error = NetworkError;
 
-    fn encode(&mut self, mut item: RawNetMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+    fn encode(&mut self, item: RawNetMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
         let header_len = item.header_buffer.len();
         let body_len = item.data.len();
-        let mut raw = item.header_buffer;
-
-        let empty_body = item.data.is_empty();
-        if empty_body {
-            // trick unsplit into actually doing something
-            item.data.resize(1, 0);
-        }
-        assert_can_unsplit(&raw, &item.data);
-        raw.unsplit(item.data);
-        if empty_body {
-            raw.truncate(raw.len() - 1);
-        }
+        let mut raw = item.into_writable(RESERVED_PREFIX);
+        let mut buf = raw.split_to(8);
+        let iv_buffer = raw.split_to(16);
 
         trace!(
             "sending raw message({} byte header + {} byte body = {} bytes): {:?}",
@@ -107,18 +114,9 @@ impl Encoder<RawNetMessage> for RawMessageEncoder {
             raw.as_ref()
         );
 
-        let iv_buffer = item
-            .iv_buffer
-            .unwrap_or_else(|| BytesMut::from(&[0; 16][..]));
-        debug_assert_eq!(16, iv_buffer.len());
-
         assert_can_unsplit(&iv_buffer, &raw);
         let encrypted = symmetric_encrypt_with_iv_buffer(iv_buffer, raw, &self.key);
 
-        let mut buf = item
-            .frame_header_buffer
-            .unwrap_or_else(|| BytesMut::from(&[0; 8][..]));
-        debug_assert_eq!(8, buf.len());
         buf.clear();
         buf.extend_from_slice(&u32::to_le_bytes(encrypted.len() as u32));
         buf.extend_from_slice(&MAGIC);
@@ -151,43 +149,56 @@ pub async fn encode_message<T: NetMessage, S: Sink<BytesMut, Error = NetworkErro
     Ok(())
 }
 
-#[instrument]
-pub async fn connect<A: ToSocketAddrs + Debug>(
-    addr: A,
+#[instrument(skip(config))]
+pub async fn connect(
+    addr: SocketAddr,
+    config: &TransportConfig,
+    limits: ParseLimits,
 ) -> Result<(
     impl Sink<RawNetMessage, Error = NetworkError>,
     impl Stream<Item = Result<RawNetMessage>>,
 )> {
-    let stream = TcpStream::connect(addr).await?;
+    let stream = config
+        .connect_tcp(&addr.ip().to_string(), addr.port())
+        .await?;
     debug!("connected to server");
     let (read, write) = stream.into_split();
-    let mut raw_reader = FramedRead::new(read, FrameCodec);
-    let mut raw_writer = FramedWrite::new(write, FrameCodec);
-
-    let encrypt_request = RawNetMessage::read(raw_reader.next().await.ok_or(NetworkError::EOF)??)?
-        .into_message::<ChannelEncryptRequest>()?;
-
-    trace!("using nonce: {:?}", encrypt_request.nonce);
-    let key = generate_session_key(None);
-
-    trace!("generated session keys: {:?}", key.plain);
-    trace!("  encrypted: {:?}", key.encrypted);
-
-    let response = ClientEncryptResponse {
-        protocol: encrypt_request.protocol,
-        encrypted_key: key.encrypted,
-    };
-    encode_message(&NetMessageHeader::default(), &response, &mut raw_writer).await?;
-
-    let encrypt_response = RawNetMessage::read(raw_reader.next().await.ok_or(NetworkError::EOF)??)?
-        .into_message::<ChannelEncryptResult>()?;
-
-    if encrypt_response.result != 1 {
-        return Err(NetworkError::CryptoHandshakeFailed);
-    }
-
-    debug!("crypt handshake complete");
-    let key = key.plain;
+    let mut raw_reader = FramedRead::new(read, FrameCodec { limits });
+    let mut raw_writer = FramedWrite::new(write, FrameCodec { limits });
+
+    let key = config
+        .handshake(async {
+            let encrypt_request =
+                RawNetMessage::read(raw_reader.next().await.ok_or(NetworkError::EOF)??)?
+                    .into_message::<ChannelEncryptRequest>()?;
+
+            trace!("using nonce: {:?}", encrypt_request.nonce);
+            let key = match config.encryption_key() {
+                Some(public_key) => generate_session_key_with(public_key, None),
+                None => generate_session_key(None),
+            };
+
+            trace!("generated session keys: {:?}", key.plain);
+            trace!("  encrypted: {:?}", key.encrypted);
+
+            let response = ClientEncryptResponse {
+                protocol: encrypt_request.protocol,
+                encrypted_key: key.encrypted,
+            };
+            encode_message(&NetMessageHeader::default(), &response, &mut raw_writer).await?;
+
+            let encrypt_response =
+                RawNetMessage::read(raw_reader.next().await.ok_or(NetworkError::EOF)??)?
+                    .into_message::<ChannelEncryptResult>()?;
+
+            if encrypt_response.result != 1 {
+                return Err(NetworkError::CryptoHandshakeFailed);
+            }
+
+            debug!("crypt handshake complete");
+            Ok(key.plain)
+        })
+        .await?;
 
     Ok((
         FramedWrite::new(raw_writer.into_inner(), RawMessageEncoder { key }),
@@ -200,7 +211,8 @@ pub async fn connect<A: ToSocketAddrs + Debug>(
                     }
                     ready(decrypted)
                 })
-                .and_then(|raw| ready(RawNetMessage::read(raw))),
+                .and_then(move |raw| ready(RawNetMessage::read_with_limits(raw, &limits))),
+            limits,
         ),
     ))
 }
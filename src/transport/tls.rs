@@ -0,0 +1,62 @@
+use rustls::crypto::CryptoProvider;
+use rustls::{ClientConfig, KeyLogFile, RootCertStore};
+use std::sync::Arc;
+
+/// TLS settings for the websocket transport
+#[derive(Debug, Default, Clone)]
+pub struct TlsConfig {
+    root_store: Option<Arc<RootCertStore>>,
+    crypto_provider: Option<Arc<CryptoProvider>>,
+    key_log: bool,
+}
+
+impl TlsConfig {
+    /// Trust the certificates from a custom root store instead of the bundled webpki roots
+    ///
+    /// This is required when connecting through a TLS-intercepting proxy.
+    pub fn with_root_store(self, root_store: RootCertStore) -> Self {
+        TlsConfig {
+            root_store: Some(Arc::new(root_store)),
+            ..self
+        }
+    }
+
+    /// Use a specific crypto provider
+    ///
+    /// If no provider is set, the process-wide default provider is used if one is installed,
+    /// otherwise the aws-lc provider is used without installing it process-wide.
+    pub fn with_crypto_provider(self, crypto_provider: Arc<CryptoProvider>) -> Self {
+        TlsConfig {
+            crypto_provider: Some(crypto_provider),
+            ..self
+        }
+    }
+
+    /// Log the TLS session keys to the file specified by the `SSLKEYLOGFILE` environment variable
+    ///
+    /// Disabled by default.
+    pub fn with_key_log(self, key_log: bool) -> Self {
+        TlsConfig { key_log, ..self }
+    }
+
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, rustls::Error> {
+        let crypto_provider = self
+            .crypto_provider
+            .clone()
+            .or_else(|| CryptoProvider::get_default().cloned())
+            .unwrap_or_else(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()));
+        let root_store = self.root_store.clone().unwrap_or_else(|| {
+            let mut root_store = RootCertStore::empty();
+            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            Arc::new(root_store)
+        });
+        let mut config = ClientConfig::builder_with_provider(crypto_provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+        if self.key_log {
+            config.key_log = Arc::new(KeyLogFile::new());
+        }
+        Ok(config)
+    }
+}
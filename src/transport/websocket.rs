@@ -1,43 +1,148 @@
-use crate::net::NetworkError;
+use crate::net::{NetworkError, ParseLimits};
+use crate::transport::TransportConfig;
 use bytes::{Bytes, BytesMut};
-use futures_util::{Sink, SinkExt, StreamExt, TryStreamExt};
-use rustls::{ClientConfig, KeyLogFile, RootCertStore};
-use std::future::ready;
+use futures_util::{Sink, SinkExt, StreamExt};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::{interval_at, Instant, MissedTickBehavior};
+use tokio::{select, spawn};
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::Stream;
-use tokio_tungstenite::tungstenite::{Message as WsMessage, Message};
-use tokio_tungstenite::{connect_async_tls_with_config, Connector};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::error::{CapacityError, UrlError};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+use tokio_tungstenite::{client_async_tls_with_config, Connector, WebSocketStream};
+use tokio_util::sync::PollSender;
 use tracing::{debug, instrument};
 
 type Result<T, E = NetworkError> = std::result::Result<T, E>;
 
-#[instrument]
+#[instrument(skip(config))]
 pub async fn connect(
     addr: &str,
+    config: &TransportConfig,
+    limits: ParseLimits,
 ) -> Result<(
     impl Sink<BytesMut, Error = NetworkError>,
     impl Stream<Item = Result<BytesMut>>,
 )> {
-    rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .ok(); // can only be once called
-    let mut root_store = RootCertStore::empty();
-    root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-    let mut tls_config = ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth();
-    tls_config.key_log = Arc::new(KeyLogFile::new());
-    let tls_config = Connector::Rustls(Arc::new(tls_config));
-    let (stream, _) = connect_async_tls_with_config(addr, None, false, Some(tls_config)).await?;
+    let tls_config = Connector::Rustls(Arc::new(config.tls().client_config()?));
+    let request = addr.into_client_request()?;
+    let host = request
+        .uri()
+        .host()
+        .ok_or(WsError::Url(UrlError::NoHostName))?;
+    // the uri keeps the brackets around ipv6 addresses
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let port = request.uri().port_u16().unwrap_or(443);
+    let stream = config.connect_tcp(host, port).await?;
+    let max_size = limits.max_frame_size();
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(max_size),
+        max_frame_size: Some(max_size),
+        ..WebSocketConfig::default()
+    };
+    let (stream, _) = config
+        .handshake(async {
+            Ok(
+                client_async_tls_with_config(request, stream, Some(ws_config), Some(tls_config))
+                    .await?,
+            )
+        })
+        .await?;
     debug!("connected to websocket server");
-    let (raw_write, raw_read) = stream.split();
+
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(16);
+    let (incoming_tx, incoming_rx) = mpsc::channel(16);
+    spawn(drive(
+        stream,
+        outgoing_rx,
+        incoming_tx,
+        config.ping_interval(),
+    ));
 
     Ok((
-        raw_write.with(|msg: BytesMut| ready(Ok(WsMessage::binary(msg)))),
-        raw_read
-            .map_err(NetworkError::from)
-            .map_ok(Message::into_data)
-            .map_ok(Bytes::from)
-            .map_ok(BytesMut::from),
+        PollSender::new(outgoing_tx).sink_map_err(|_| NetworkError::ConnectionClosed),
+        ReceiverStream::new(incoming_rx),
     ))
 }
+
+/// Move messages between the websocket and the channels, handling control frames and idle pings
+///
+/// Pings from the server are answered by tungstenite, the replies are flushed here.
+/// Closing the outgoing channel sends a close frame to the server.
+async fn drive<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: WebSocketStream<S>,
+    mut outgoing: mpsc::Receiver<BytesMut>,
+    incoming: mpsc::Sender<Result<BytesMut>>,
+    ping_interval: Duration,
+) {
+    let mut ping = interval_at(Instant::now() + ping_interval, ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        select! {
+            msg = outgoing.recv() => match msg {
+                Some(msg) => {
+                    if let Err(e) = stream.send(WsMessage::binary(msg)).await {
+                        incoming.send(Err(e.into())).await.ok();
+                        break;
+                    }
+                    ping.reset();
+                }
+                None => {
+                    debug!("closing websocket");
+                    stream.close(None).await.ok();
+                    break;
+                }
+            },
+            msg = stream.next() => match msg {
+                Some(Ok(WsMessage::Binary(data))) => {
+                    if incoming.send(Ok(BytesMut::from(Bytes::from(data)))).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(WsMessage::Close(frame))) => {
+                    let (code, reason) = match frame {
+                        Some(frame) => (frame.code.into(), frame.reason.into_owned()),
+                        None => (CloseCode::Status.into(), String::new()),
+                    };
+                    debug!(code, reason, "websocket closed by server");
+                    // send the close reply
+                    stream.flush().await.ok();
+                    // the last item of the stream, which the connection turns into a clean close
+                    incoming.send(Err(NetworkError::WebSocketClosed { code, reason })).await.ok();
+                    break;
+                }
+                Some(Ok(WsMessage::Ping(_))) => {
+                    stream.flush().await.ok();
+                }
+                Some(Ok(frame)) => {
+                    debug!(frame = ?frame, "ignoring non-binary websocket frame");
+                }
+                Some(Err(WsError::Capacity(CapacityError::MessageTooLong { size, max_size }))) => {
+                    incoming.send(Err(NetworkError::FrameTooLarge { size, limit: max_size })).await.ok();
+                    break;
+                }
+                Some(Err(e)) => {
+                    incoming.send(Err(e.into())).await.ok();
+                    break;
+                }
+                None => break,
+            },
+            _ = ping.tick() => {
+                debug!("sending keepalive ping");
+                if let Err(e) = stream.send(WsMessage::Ping(Vec::new())).await {
+                    incoming.send(Err(e.into())).await.ok();
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+use steam_vent::connection::UnAuthenticatedConnection;
+use steam_vent::proto::steammessages_gameservers_steamclient::{
+    cgame_servers_get_server_list_response, CGameServers_GetServerList_Request,
+    CGameServers_GetServerList_Response,
+};
+use steam_vent::testing::MockCmServer;
+use steam_vent::{
+    Backoff, Connection, ConnectionError, ConnectionEvent, ConnectionOptions, ConnectionTrait,
+    EResult, HeartbeatOptions, LoginError, ReconnectEvent, ReconnectOptions,
+    ReconnectingConnection, Transport,
+};
+use tokio::time::timeout;
+use tokio_stream::{Stream, StreamExt};
+
+const TRANSPORTS: [Transport; 2] = [Transport::WebSocket, Transport::Tcp];
+
+async fn start(transport: Transport) -> MockCmServer {
+    let server = MockCmServer::start(transport)
+        .await
+        .expect("failed to start mock server");
+    server.add_handler(|req: CGameServers_GetServerList_Request| async move {
+        Ok(CGameServers_GetServerList_Response {
+            servers: vec![cgame_servers_get_server_list_response::Server {
+                name: Some(req.filter().as_bytes().to_vec()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        })
+    });
+    server
+}
+
+async fn connect(server: &MockCmServer) -> Result<Connection, ConnectionError> {
+    UnAuthenticatedConnection::connect_with_config(
+        &server.server_list(),
+        &server.transport_config(),
+    )
+    .await?
+    .anonymous()
+    .await
+}
+
+async fn get_server_list(connection: &impl ConnectionTrait) -> String {
+    let mut req = CGameServers_GetServerList_Request::new();
+    req.set_filter(r"\appid\440".into());
+    let response = connection
+        .service_method(req)
+        .await
+        .expect("service method failed");
+    String::from_utf8(response.servers[0].name().to_vec()).unwrap()
+}
+
+/// Wait for the first event matching the predicate
+async fn wait_for_event(
+    events: impl Stream<Item = ConnectionEvent>,
+    predicate: impl Fn(&ConnectionEvent) -> bool,
+) -> ConnectionEvent {
+    let mut events = std::pin::pin!(events);
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let event = events.next().await.expect("event stream ended");
+            if predicate(&event) {
+                return event;
+            }
+        }
+    })
+    .await
+    .expect("timed out waiting for event")
+}
+
+#[tokio::test]
+async fn service_method_call() {
+    for transport in TRANSPORTS {
+        let server = start(transport).await;
+        let connection = connect(&server).await.expect("failed to connect");
+
+        assert_eq!(get_server_list(&connection).await, r"\appid\440");
+    }
+}
+
+#[tokio::test]
+async fn service_method_without_handler() {
+    let server = MockCmServer::start(Transport::WebSocket).await.unwrap();
+    let connection = connect(&server).await.expect("failed to connect");
+
+    let err = connection
+        .service_method(CGameServers_GetServerList_Request::new())
+        .await
+        .unwrap_err();
+    assert_eq!(err.eresult(), Some(EResult::Fail));
+}
+
+#[tokio::test]
+async fn logon_failure() {
+    for transport in TRANSPORTS {
+        let server = start(transport).await;
+        server.set_logon_result(EResult::InvalidPassword);
+
+        let err = connect(&server).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ConnectionError::LoginError(LoginError::InvalidCredentials)
+            ),
+            "unexpected error {err:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn reconnect_after_disconnect() {
+    for transport in TRANSPORTS {
+        let server = start(transport).await;
+        let connection = connect(&server).await.expect("failed to connect");
+        let options = ReconnectOptions::default()
+            .with_backoff(Backoff::default().with_initial(Duration::from_millis(10)));
+        let connection = ReconnectingConnection::new(connection, server.server_list(), options);
+        let events = connection.events();
+        let first_session = connection.connection().session_id();
+
+        server.disconnect().await;
+        wait_for_event(events, |event| {
+            matches!(
+                event,
+                ConnectionEvent::Reconnect(ReconnectEvent::Reconnected)
+            )
+        })
+        .await;
+
+        assert_ne!(connection.connection().session_id(), first_session);
+        assert_eq!(get_server_list(&connection).await, r"\appid\440");
+        assert_eq!(server.connection_count(), 1);
+    }
+}
+
+#[tokio::test]
+async fn heartbeat_timeout() {
+    for transport in TRANSPORTS {
+        let server = start(transport).await;
+        let heartbeat = HeartbeatOptions::default()
+            .with_interval(Duration::from_millis(50))
+            .with_max_missed(2);
+        let connection = UnAuthenticatedConnection::connect_with_options(
+            &server.server_list(),
+            &server.transport_config(),
+            &ConnectionOptions::default().with_heartbeat(heartbeat),
+        )
+        .await
+        .expect("failed to connect")
+        .anonymous()
+        .await
+        .expect("failed to log on");
+        let events = connection.events();
+
+        // the mock server doesn't send anything unless asked, so the connection goes silent
+        let mut events = std::pin::pin!(events);
+        wait_for_event(&mut events, |event| {
+            matches!(event, ConnectionEvent::HeartbeatTimeout)
+        })
+        .await;
+        wait_for_event(&mut events, |event| {
+            matches!(event, ConnectionEvent::TransportClosed(_))
+        })
+        .await;
+        assert!(!connection.is_connected());
+    }
+}